@@ -0,0 +1,80 @@
+#!/bin/bash
+# Starts N sprayer daemons in their own host namespaces on a shared fabric
+# bridge, all joining through host1. Then stops the last one and checks that
+# every daemon saw all the others join and the others saw the last one die.
+# Exits non-zero otherwise. Run from the repository root after building
+# sprayer.
+#
+#   ./gossip-test.sh [hosts] [seconds]
+set -e
+
+hosts=${1:-4}
+wait=${2:-15}
+sprayer=${SPRAYER:-sprayer/target/debug/sprayer}
+
+cleanup() {
+  for i in $(seq 1 ${hosts}); do
+    sudo ip netns pids host${i} 2>/dev/null | xargs -r sudo kill || true
+    sudo ip netns del host${i} 2>/dev/null || true
+    sudo ip netns del ns${i} 2>/dev/null || true
+    sudo rm -rf /sys/fs/bpf/sprayer-host${i}
+  done
+  sudo ip link del fabric 2>/dev/null || true
+}
+trap cleanup EXIT
+
+sudo ip link add name fabric type bridge
+sudo ip link set dev fabric up
+
+for i in $(seq 1 ${hosts}); do
+  host=host${i}
+  ns=ns${i}
+  sudo ip netns add ${host}
+  sudo ip link add name ${host}-phy type veth peer name ${host}-fab
+  sudo ip link set dev ${host}-fab up
+  sudo ip link set dev ${host}-fab master fabric
+  sudo ip link set dev ${host}-phy netns ${host}
+  sudo ip netns exec ${host} ip link set dev lo up
+  sudo ip netns exec ${host} ip link set dev ${host}-phy up
+  sudo ip netns exec ${host} ip addr add 192.168.0.${i}/24 dev ${host}-phy
+
+  sudo ip netns add ${ns}
+  sudo ip link add name ${ns} type veth peer name ${host}-${ns}
+  sudo ip link set ${ns} netns ${ns}
+  sudo ip link set ${host}-${ns} netns ${host}
+  sudo ip netns exec ${host} ip link set ${host}-${ns} up
+  sudo ip netns exec ${ns} ip link set ${ns} up
+  sudo ip netns exec ${ns} ip addr add 10.0.0.${i}/24 dev ${ns}
+done
+
+for i in $(seq 1 ${hosts}); do
+  host=host${i}
+  sudo ip netns exec ${host} env RUST_LOG=info ${sprayer} encap \
+    --iface ${host}-ns${i} --phy ${host}-phy \
+    --pin-path /sys/fs/bpf/sprayer-${host} \
+    --gossip --gossip-seed 192.168.0.1 > /tmp/sprayer-${host}.log 2>&1 &
+done
+
+sleep ${wait}
+sudo ip netns pids host${hosts} | xargs -r sudo kill || true
+# Suspicion times out after 5s, give the news a few periods to spread.
+sleep 10
+
+failed=0
+for i in $(seq 1 ${hosts}); do
+  log=/tmp/sprayer-host${i}.log
+  joined=$(grep -c "joined" ${log} || true)
+  if [ "${joined}" -lt $((hosts - 1)) ]; then
+    echo "host${i}: saw ${joined} of $((hosts - 1)) members join"
+    failed=1
+  fi
+  if [ ${i} -lt ${hosts} ] && ! grep -q "member 192.168.0.${hosts} is dead" ${log}; then
+    echo "host${i}: did not see host${hosts} die"
+    failed=1
+  fi
+done
+if [ ${failed} -eq 0 ]; then
+  echo "ok"
+fi
+exit ${failed}
+
@@ -0,0 +1,196 @@
+//! Packet access and ICMP error helpers shared by the eBPF programs.
+
+use core::mem;
+
+use aya_bpf::{
+    helpers::{bpf_csum_diff, bpf_xdp_get_buff_len},
+    programs::{TcContext, XdpContext},
+};
+use network_types::{
+    eth::{EthHdr, EtherType},
+    ip::{IpProto, Ipv4Hdr},
+};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct IcmpHdr {
+    pub icmp_type: u8,
+    pub code: u8,
+    pub check: u16,
+    pub unused: u32,
+}
+
+impl IcmpHdr {
+    pub const LEN: usize = mem::size_of::<IcmpHdr>();
+}
+
+pub const ICMP_DEST_UNREACH: u8 = 3;
+pub const ICMP_TIME_EXCEEDED: u8 = 11;
+pub const ICMP_HOST_UNREACH: u8 = 1;
+pub const ICMP_FRAG_NEEDED: u8 = 4;
+pub const ICMP_HOST_ANO: u8 = 10;
+/// The offending IP header (without options) and the first 8 payload bytes.
+pub const ICMP_QUOTE_LEN: usize = Ipv4Hdr::LEN + 8;
+
+/// The packet of an XDP or TC program, so header code can serve both.
+pub trait PacketCtx {
+    fn data(&self) -> usize;
+    fn data_end(&self) -> usize;
+    /// Length of the whole packet, fragments included.
+    fn len(&self) -> u32;
+}
+
+impl PacketCtx for XdpContext {
+    fn data(&self) -> usize {
+        XdpContext::data(self)
+    }
+
+    fn data_end(&self) -> usize {
+        XdpContext::data_end(self)
+    }
+
+    fn len(&self) -> u32 {
+        unsafe { bpf_xdp_get_buff_len(self.ctx) as u32 }
+    }
+}
+
+impl PacketCtx for TcContext {
+    fn data(&self) -> usize {
+        TcContext::data(self)
+    }
+
+    fn data_end(&self) -> usize {
+        TcContext::data_end(self)
+    }
+
+    fn len(&self) -> u32 {
+        TcContext::len(self)
+    }
+}
+
+impl<C: PacketCtx> PacketCtx for &C {
+    fn data(&self) -> usize {
+        (*self).data()
+    }
+
+    fn data_end(&self) -> usize {
+        (*self).data_end()
+    }
+
+    fn len(&self) -> u32 {
+        (*self).len()
+    }
+}
+
+#[inline(always)]
+pub fn ptr_at<T>(ctx: &impl PacketCtx, offset: usize) -> Option<*const T> {
+    let start = ctx.data();
+    let end = ctx.data_end();
+    let len = mem::size_of::<T>();
+
+    if start + offset + len > end {
+        return None;
+    }
+
+    Some((start + offset) as *const T)
+}
+
+#[inline(always)]
+pub fn ptr_at_mut<T>(ctx: &impl PacketCtx, offset: usize) -> Option<*mut T> {
+    let ptr = ptr_at::<T>(ctx, offset)?;
+    Some(ptr as *mut T)
+}
+
+/// Decrements the TTL, false if the packet must not be forwarded anymore.
+#[inline(always)]
+pub fn decrement_ttl(ip_hdr: *mut Ipv4Hdr) -> bool {
+    let ttl = unsafe { (*ip_hdr).ttl };
+    if ttl <= 1 {
+        return false;
+    }
+    // RFC 1624 incremental update, as ip_decrease_ttl() does.
+    let check = unsafe { (*ip_hdr).check } as u32 + u16::to_be(0x0100) as u32;
+    unsafe {
+        (*ip_hdr).check = (check + (check >= 0xffff) as u32) as u16;
+        (*ip_hdr).ttl = ttl - 1;
+    }
+    true
+}
+
+/// Whether the IP header at `offset` can be quoted in an ICMP error: long
+/// enough, without options, and not an ICMP error itself.
+#[inline(always)]
+pub fn quotable(ctx: &impl PacketCtx, offset: usize) -> bool {
+    let quote = match ptr_at::<[u8; ICMP_QUOTE_LEN]>(&ctx, offset) {
+        Some(quote) => unsafe { *quote },
+        None => return false,
+    };
+    if quote[0] != 0x45 {
+        return false;
+    }
+    // Only echo requests and replies among ICMP messages.
+    quote[9] != IpProto::Icmp as u8 || quote[Ipv4Hdr::LEN] == 0 || quote[Ipv4Hdr::LEN] == 8
+}
+
+/// Writes Ethernet, IP and ICMP error headers at `offset`, in front of the
+/// quoted header already in place behind them.
+#[inline(always)]
+pub fn write_icmp_error(
+    ctx: &impl PacketCtx,
+    offset: usize,
+    icmp_type: u8,
+    code: u8,
+    rest: u32,
+    dst_mac: [u8; 6],
+    src_mac: [u8; 6],
+    src_ip: u32,
+    dst_ip: u32,
+) -> Option<()> {
+    let eth_hdr = ptr_at_mut::<EthHdr>(&ctx, offset)?;
+    let ip_hdr = ptr_at_mut::<Ipv4Hdr>(&ctx, offset + EthHdr::LEN)?;
+    let icmp_hdr = ptr_at_mut::<IcmpHdr>(&ctx, offset + EthHdr::LEN + Ipv4Hdr::LEN)?;
+    let icmp = ptr_at_mut::<[u32; (IcmpHdr::LEN + ICMP_QUOTE_LEN) / 4]>(&ctx, offset + EthHdr::LEN + Ipv4Hdr::LEN)?;
+    let quote = ptr_at::<Ipv4Hdr>(&ctx, offset + EthHdr::LEN + Ipv4Hdr::LEN + IcmpHdr::LEN)?;
+    unsafe {
+        eth_hdr.write(EthHdr {
+            dst_addr: dst_mac,
+            src_addr: src_mac,
+            ether_type: EtherType::Ipv4,
+        });
+        ip_hdr.write(Ipv4Hdr {
+            _bitfield_1: (*quote)._bitfield_1,
+            _bitfield_align_1: (*quote)._bitfield_align_1,
+            tos: 0,
+            tot_len: u16::to_be((Ipv4Hdr::LEN + IcmpHdr::LEN + ICMP_QUOTE_LEN) as u16),
+            id: 0,
+            frag_off: 0,
+            ttl: 64,
+            proto: IpProto::Icmp,
+            check: 0,
+            src_addr: src_ip,
+            dst_addr: dst_ip,
+        });
+        icmp_hdr.write(IcmpHdr {
+            icmp_type,
+            code,
+            check: 0,
+            unused: rest,
+        });
+        (*icmp_hdr).check = csum(icmp as *mut u32, (IcmpHdr::LEN + ICMP_QUOTE_LEN) as u32);
+        (*ip_hdr).check = csum(ip_hdr as *mut u32, Ipv4Hdr::LEN as u32);
+    }
+    Some(())
+}
+
+/// Internet checksum over `len` bytes of bounds checked packet data.
+#[inline(always)]
+pub fn csum(data: *mut u32, len: u32) -> u16 {
+    let mut sum = unsafe { bpf_csum_diff(core::ptr::null_mut(), 0, data, len, 0) } as u64;
+    for _ in 0..4 {
+        if sum >> 16 == 0 {
+            break;
+        }
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
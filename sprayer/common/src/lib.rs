@@ -1,14 +1,100 @@
 #![no_std]
 
+#[cfg(feature = "ebpf")]
+pub mod ebpf;
+
+pub const TUNNEL_PORT: u16 = 3000;
+pub const CRYPT_TUNNEL_PORT: u16 = 3001;
+
+/// Destination ports of tunnel packets, the same on every sprayer. Spray
+/// paths spread plain tunnels over `count` ports from `base` so receivers
+/// hash them to different queues.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TunnelPorts {
+    pub base: u32,
+    pub count: u32,
+    pub crypt: u32,
+}
+
+impl Default for TunnelPorts {
+    fn default() -> Self {
+        TunnelPorts {
+            base: TUNNEL_PORT as u32,
+            count: 1,
+            crypt: CRYPT_TUNNEL_PORT as u32,
+        }
+    }
+}
+
+impl TunnelPorts {
+    pub fn is_plain(&self, port: u16) -> bool {
+        (port as u32).wrapping_sub(self.base) < self.count
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for TunnelPorts {}
+
+/// Outer UDP source port of spray path 0, path n uses the port n above it.
+pub const SPRAY_BASE_PORT: u16 = 1000;
+pub const MAX_PATHS: usize = 16;
+/// Size of the XSKMAPs, rx queues past it cannot reach an AF_XDP socket.
+pub const MAX_XSK_QUEUES: u32 = 64;
+
+pub const NETWORK_FLAG_ENCRYPT: u32 = 1;
+/// Mark outer headers with `Network::dscp` instead of the inner DSCP.
+pub const NETWORK_FLAG_DSCP: u32 = 2;
+/// Look the underlay next hop up in `Network::fib_table`, or the main table,
+/// skipping policy rules.
+pub const NETWORK_FLAG_FIB_DIRECT: u32 = 4;
+/// Number outer headers from a per-CPU counter instead of copying the inner ID.
+pub const NETWORK_FLAG_ID_COUNTER: u32 = 8;
+
+/// The outer DF bit is a copy of the inner one.
+pub const OUTER_DF_INHERIT: u32 = 0;
+pub const OUTER_DF_SET: u32 = 1;
+pub const OUTER_DF_CLEAR: u32 = 2;
+
+/// Every packet takes a random path, weighted by congestion feedback.
+pub const SPRAY_RANDOM: u32 = 0;
+/// A flow moves to another path only after pausing for `Network::flowlet_gap`.
+pub const SPRAY_FLOWLET: u32 = 1;
+/// Every packet takes the next path.
+pub const SPRAY_ROUND_ROBIN: u32 = 2;
+/// Each flow sticks to the path its inner 5-tuple hashes to (RFC 7510).
+pub const SPRAY_HASH: u32 = 3;
+/// Everything takes path 0.
+pub const SPRAY_NONE: u32 = 4;
+
+pub const DEFAULT_FLOWLET_GAP_US: u32 = 500;
+
 #[repr(C)]
 #[derive(Clone, Copy)]
-pub struct NetworkKey {
+pub struct Network {
+    pub gateway: u32,
+    pub flags: u32,
+    pub dscp: u32,
+    pub spray: u32,
+    /// Microseconds.
+    pub flowlet_gap: u32,
+    /// Underlay routing table, 0 for the main table.
+    pub fib_table: u32,
+    /// Outer source address, 0 for the physical interface's.
+    pub src: u32,
+    /// Outer TTL, 0 to copy the inner one.
+    pub ttl: u32,
+    /// One of the `OUTER_DF_*` policies.
+    pub df: u32,
+    /// The network's own prefix, which its spray exceptions are keyed by.
     pub prefix: u32,
-    pub prefix_len: u8
+    pub prefix_len: u32,
 }
 
 #[cfg(feature = "user")]
-unsafe impl aya::Pod for NetworkKey {}
+unsafe impl aya::Pod for Network {}
+
+pub const INTERFACE_FLAG_LOCAL: u32 = 1;
 
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -16,11 +102,22 @@ pub struct Interface {
     pub mac: [u8;6],
     pub ifidx: u32,
     pub next_hop: u32,
+    pub flags: u32,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for Interface {}
 
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct LearnedEndpoint {
+    pub underlay_ip: u32,
+    pub last_seen: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for LearnedEndpoint {}
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct FlowKey {
@@ -42,7 +139,81 @@ pub struct FlowNextHop {
     pub src_ip: u32,
     pub dst_ip: u32,
     pub ifidx: u32,
+    /// Spray path of the current flowlet and when the flow last sent.
+    pub path: u32,
+    pub flags: u32,
+    pub last_seen: u64,
+    /// Inner bytes encapsulated so far.
+    pub bytes: u64,
+}
+
+/// The flow crossed the elephant threshold and is sprayed.
+pub const FLOW_FLAG_ELEPHANT: u32 = 1;
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowNextHop {}
+
+/// A spray path towards or from a remote sprayer.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PathKey {
+    pub remote: u32,
+    pub path: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PathKey {}
+
+/// Tunnel packets received on a path and how many of them had their outer
+/// header marked congestion experienced.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct PathStats {
+    pub packets: u64,
+    pub ce: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PathStats {}
+
+/// Per path share of the traffic towards a remote, 255 for an uncongested path.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PathWeights {
+    pub weights: [u8; MAX_PATHS],
 }
 
 #[cfg(feature = "user")]
-unsafe impl aya::Pod for FlowNextHop {}
\ No newline at end of file
+unsafe impl aya::Pod for PathWeights {}
+
+/// Traffic of a network with spray policy exceptions: an IP protocol and a
+/// port on either side, port 0 matching every port of the protocol.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SprayExceptionKey {
+    pub prefix: u32,
+    pub prefix_len: u8,
+    pub ip_proto: u8,
+    pub port: u16,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for SprayExceptionKey {}
+
+/// Drop tunnel packets from underlay addresses missing from PEERS.
+pub const PEER_CHECK_SOURCE: u32 = 1;
+/// Also drop those whose inner source is not an endpoint behind the sender.
+pub const PEER_CHECK_INNER: u32 = 2;
+
+/// Slots of the spoofing counters.
+pub const SPOOF_UNKNOWN_PEER: u32 = 0;
+pub const SPOOF_INNER_SOURCE: u32 = 1;
+pub const MAX_SPOOF_RESULTS: u32 = 2;
+
+/// Slots of the fib lookup outcome counters, indexed by BPF_FIB_LKUP_RET_*.
+pub const MAX_FIB_RESULTS: u32 = 16;
+/// Slot counting lookups the helper rejected with an error.
+pub const FIB_RESULT_ERROR: u32 = MAX_FIB_RESULTS - 1;
+/// Slot counting packets without DF that were too big and went to the stack
+/// to be fragmented.
+pub const FIB_RESULT_FRAGMENT: u32 = MAX_FIB_RESULTS - 2;
@@ -0,0 +1,66 @@
+use std::io::Error;
+
+use log::{info, warn};
+
+use crate::packet::PacketSocket;
+
+const ARP_FRAME_LEN: usize = 42;
+
+fn gratuitous_arp(mac: [u8; 6], ip: u32) -> [u8; ARP_FRAME_LEN] {
+    let mut frame = [0u8; ARP_FRAME_LEN];
+    frame[0..6].copy_from_slice(&[0xff; 6]);
+    frame[6..12].copy_from_slice(&mac);
+    frame[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+    // htype ethernet, ptype ipv4, hlen, plen, oper request
+    frame[14..22].copy_from_slice(&[0, 1, 8, 0, 6, 4, 0, 1]);
+    frame[22..28].copy_from_slice(&mac);
+    frame[28..32].copy_from_slice(&ip.to_be_bytes());
+    frame[38..42].copy_from_slice(&ip.to_be_bytes());
+    frame
+}
+
+/// Announces the proxy MAC for overlay addresses into workload namespaces,
+/// so neighbour entries learned before the proxy MAC was set or changed get
+/// refreshed.
+pub struct Announcer {
+    sock: PacketSocket,
+    proxy_mac: [u8; 6],
+    // Host side port of each local workload and the addresses announced to it.
+    targets: Vec<(u32, Vec<u32>)>,
+}
+
+impl Announcer {
+    pub fn new(proxy_mac: [u8; 6], targets: Vec<(u32, Vec<u32>)>) -> Result<Self, Error> {
+        let announcer = Announcer {
+            sock: PacketSocket::new()?,
+            proxy_mac,
+            targets,
+        };
+        announcer.announce();
+        Ok(announcer)
+    }
+
+    pub fn announce(&self) {
+        for (ifidx, ips) in &self.targets {
+            for ip in ips {
+                if let Err(e) = self.sock.send(*ifidx, &gratuitous_arp(self.proxy_mac, *ip)) {
+                    warn!("failed to send gratuitous arp on ifidx {}: {}", ifidx, e);
+                }
+            }
+        }
+    }
+
+    /// Replaces the workloads announced to, announcing to them right away.
+    pub fn set_targets(&mut self, targets: Vec<(u32, Vec<u32>)>) {
+        self.targets = targets;
+        self.announce();
+    }
+
+    pub fn update(&mut self, proxy_mac: [u8; 6]) {
+        if proxy_mac != self.proxy_mac {
+            info!("proxy mac changed, sending gratuitous arps");
+            self.proxy_mac = proxy_mac;
+            self.announce();
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt;
+
+use anyhow::Context;
+use aya::programs::{xdp::XdpLinkId, Xdp, XdpFlags};
+use log::{info, warn};
+use sprayer::netif::get_matching_interfaces;
+
+/// How XDP programs are attached to interfaces.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XdpMode {
+    /// In the driver, fails on interfaces without native XDP support
+    Native,
+    /// In the generic SKB path, works everywhere but is slower
+    Generic,
+    /// On the NIC itself
+    Offload,
+    /// Native, falling back to generic per interface
+    Auto,
+}
+
+/// The mode xdp_dummy is attached in on the peers of veth redirect targets,
+/// whatever --xdp-mode says. A veth only takes redirected frames when its
+/// peer runs a program in the driver, a generic one does not count.
+pub const VETH_PEER_MODE: XdpMode = XdpMode::Native;
+
+impl XdpMode {
+    pub fn flags(self) -> XdpFlags {
+        match self {
+            XdpMode::Native | XdpMode::Auto => XdpFlags::DRV_MODE,
+            XdpMode::Generic => XdpFlags::SKB_MODE,
+            XdpMode::Offload => XdpFlags::HW_MODE,
+        }
+    }
+}
+
+impl fmt::Display for XdpMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            XdpMode::Native => "native",
+            XdpMode::Generic => "generic",
+            XdpMode::Offload => "offload",
+            XdpMode::Auto => "auto",
+        })
+    }
+}
+
+struct Attached {
+    program: &'static str,
+    iface: String,
+    ifindex: u32,
+    mode: XdpMode,
+    link: XdpLinkId,
+}
+
+/// The programs attached by this daemon and the mode each one ended up in.
+#[derive(Default)]
+pub struct Attachments {
+    attached: Vec<Attached>,
+}
+
+impl Attachments {
+    /// Attaches `program` to `iface`. In auto mode an interface whose driver
+    /// refuses native XDP gets the program in generic mode instead.
+    fn attach(
+        &mut self,
+        name: &'static str,
+        program: &mut Xdp,
+        iface: &str,
+        ifindex: u32,
+        mode: XdpMode,
+    ) -> Result<XdpMode, anyhow::Error> {
+        let (link, used) = match program.attach(iface, mode.flags()) {
+            Ok(link) => (link, if mode == XdpMode::Auto { XdpMode::Native } else { mode }),
+            Err(e) if mode == XdpMode::Auto => {
+                warn!("{} cannot run natively on {} ({}), falling back to generic mode", name, iface, e);
+                let link = program.attach(iface, XdpMode::Generic.flags())
+                    .with_context(|| format!("failed to attach {} to {} in generic mode", name, iface))?;
+                (link, XdpMode::Generic)
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("failed to attach {} to {} in {} mode, try --xdp-mode auto", name, iface, mode)
+                });
+            }
+        };
+        self.attached.push(Attached { program: name, iface: iface.to_string(), ifindex, mode: used, link });
+        Ok(used)
+    }
+
+    /// Attaches `program` to the interfaces matching `patterns` that don't
+    /// have it yet and forgets the ones that disappeared. Interfaces are
+    /// told apart by index, so one recreated under the same name gets the
+    /// program again. An interface that refuses the program is logged and
+    /// retried on the next sync. The maps are those of the loaded object,
+    /// so every interface shares them.
+    pub fn sync(
+        &mut self,
+        name: &'static str,
+        program: &mut Xdp,
+        patterns: &[String],
+        mode: XdpMode,
+    ) -> Result<(), anyhow::Error> {
+        let present = get_matching_interfaces(patterns).context("failed to list interfaces")?;
+        let mut kept = Vec::with_capacity(self.attached.len());
+        for attached in self.attached.drain(..) {
+            if attached.program != name || present.iter().any(|(_, ifindex)| *ifindex == attached.ifindex) {
+                kept.push(attached);
+                continue;
+            }
+            info!("{} removed from {}", name, attached.iface);
+            // The kernel already dropped the program with the interface.
+            let _ = program.detach(attached.link);
+        }
+        self.attached = kept;
+        for (iface, ifindex) in present {
+            if self.attached.iter().any(|attached| attached.program == name && attached.ifindex == ifindex) {
+                continue;
+            }
+            match self.attach(name, program, &iface, ifindex, mode) {
+                Ok(mode) => info!("{} attached to {} in {} mode", name, iface, mode),
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// The interfaces `program` is attached to, in the order they were added.
+    pub fn interfaces<'a>(&'a self, program: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.attached.iter()
+            .filter(move |attached| attached.program == program)
+            .map(|attached| attached.iface.as_str())
+    }
+
+    pub fn log_status(&self) {
+        for attached in &self.attached {
+            info!("{} on {}: {} mode", attached.program, attached.iface, attached.mode);
+        }
+    }
+}
@@ -0,0 +1,601 @@
+//! CNI plugin attaching pods to the sprayer data path. It creates the pod
+//! veth, allocates an address from the configured overlay network and
+//! registers the pod in the INTERFACE map pinned by the running sprayer.
+//!
+//! The plugin attaches no programs. The host end of the veth is named `spr`
+//! followed by a hash, and the sprayer attaches xdp_encap to it on its next
+//! interface sync if one of its `--iface` patterns matches, e.g. `spr*`.
+//! xdp_dummy goes onto the pod end once the sprayer finds the port. Pods
+//! stay registered across restarts of the sprayer.
+//!
+//! Example network configuration:
+//!
+//! ```json
+//! {
+//!   "cniVersion": "1.0.0",
+//!   "name": "sprayer",
+//!   "type": "sprayer-cni",
+//!   "network": "10.0.0.0/24,gw=10.0.0.1",
+//!   "phy": "eth0"
+//! }
+//! ```
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context};
+use blake2::{Blake2s256, Digest};
+use common::{Interface, INTERFACE_FLAG_LOCAL};
+use serde::Deserialize;
+use serde_json::json;
+use sprayer::config::NetworkSpec;
+use sprayer::endpoints::EndpointMaps;
+use sprayer::netif::{get_interface_index, get_interface_ip_address};
+use sprayer::netns::in_netns;
+
+const SUPPORTED_VERSIONS: &[&str] = &["0.3.0", "0.3.1", "0.4.0", "1.0.0"];
+
+// Error codes from the CNI spec, plus one for everything else.
+const ERR_INCOMPATIBLE_VERSION: u32 = 1;
+const ERR_INVALID_ENV: u32 = 4;
+const ERR_DECODE: u32 = 6;
+const ERR_INVALID_CONFIG: u32 = 7;
+const ERR_INTERNAL: u32 = 100;
+
+#[derive(Debug)]
+struct CniError {
+    code: u32,
+    msg: String,
+}
+
+impl fmt::Display for CniError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+impl std::error::Error for CniError {}
+
+fn cni_error(code: u32, msg: impl Into<String>) -> anyhow::Error {
+    CniError { code, msg: msg.into() }.into()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NetConf {
+    cni_version: String,
+    name: String,
+    /// Overlay network in the daemon's --network syntax.
+    network: String,
+    /// Underlay interface of this host, its address is the pods' next hop.
+    phy: String,
+    #[serde(default = "default_pin_path")]
+    pin_path: PathBuf,
+    /// Where allocated addresses are recorded, one file per address.
+    #[serde(default = "default_data_dir")]
+    data_dir: PathBuf,
+    mtu: Option<u32>,
+}
+
+fn default_pin_path() -> PathBuf {
+    PathBuf::from("/sys/fs/bpf/sprayer")
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from("/var/lib/cni/networks")
+}
+
+struct Args {
+    container_id: String,
+    netns: Option<PathBuf>,
+    ifname: String,
+}
+
+impl Args {
+    fn from_env() -> Result<Self, anyhow::Error> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(vars: impl Fn(&str) -> Option<String>) -> Result<Self, anyhow::Error> {
+        let var = |name: &str| {
+            vars(name)
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| cni_error(ERR_INVALID_ENV, format!("{name} is not set")))
+        };
+        Ok(Args {
+            container_id: var("CNI_CONTAINERID")?,
+            netns: var("CNI_NETNS").ok().map(PathBuf::from),
+            ifname: var("CNI_IFNAME")?,
+        })
+    }
+
+    // Interface names are limited to 15 bytes, container ids are not.
+    fn host_veth(&self) -> String {
+        let hash = Blake2s256::new()
+            .chain_update(self.container_id.as_bytes())
+            .chain_update(self.ifname.as_bytes())
+            .finalize();
+        let suffix: String = hash[..6].iter().map(|b| format!("{b:02x}")).collect();
+        format!("spr{suffix}")
+    }
+}
+
+/// host-local style address management: a file named after each allocated
+/// address holds the container id and interface it belongs to.
+struct Ipam {
+    dir: PathBuf,
+}
+
+impl Ipam {
+    fn open(conf: &NetConf) -> Result<Self, anyhow::Error> {
+        let dir = conf.data_dir.join(&conf.name);
+        std::fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        Ok(Ipam { dir })
+    }
+
+    fn owner(args: &Args) -> String {
+        format!("{}\n{}\n", args.container_id, args.ifname)
+    }
+
+    fn find(&self, args: &Args) -> Result<Option<Ipv4Addr>, anyhow::Error> {
+        let owner = Self::owner(args);
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let ip: Ipv4Addr = match entry.file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(ip) => ip,
+                None => continue,
+            };
+            if std::fs::read_to_string(entry.path()).is_ok_and(|content| content == owner) {
+                return Ok(Some(ip));
+            }
+        }
+        Ok(None)
+    }
+
+    fn allocate(&self, args: &Args, network: &NetworkSpec, gateway: Ipv4Addr) -> Result<Ipv4Addr, anyhow::Error> {
+        let size = 1u64 << (32 - network.prefix_len as u32);
+        let first = u32::from(network.prefix) as u64 + 1;
+        let last = u32::from(network.prefix) as u64 + size - 2;
+        for ip in first..=last {
+            let ip = Ipv4Addr::from(ip as u32);
+            if ip == gateway {
+                continue;
+            }
+            // create_new makes concurrent ADDs race for the file, not the address.
+            match OpenOptions::new().write(true).create_new(true).open(self.dir.join(ip.to_string())) {
+                Ok(mut file) => {
+                    file.write_all(Self::owner(args).as_bytes())?;
+                    return Ok(ip);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        bail!("no free address left in {}/{}", network.prefix, network.prefix_len)
+    }
+
+    fn release(&self, ip: Ipv4Addr) -> Result<(), anyhow::Error> {
+        match std::fs::remove_file(self.dir.join(ip.to_string())) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn ip(args: &[&str]) -> Result<String, anyhow::Error> {
+    let output = Command::new("ip").args(args).output().context("failed to run ip")?;
+    if !output.status.success() {
+        bail!("ip {}: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn link_mac(name: &str) -> Result<[u8; 6], anyhow::Error> {
+    let link = ip(&["-o", "link", "show", "dev", name])?;
+    let mac = link
+        .split_whitespace()
+        .skip_while(|word| *word != "link/ether")
+        .nth(1)
+        .with_context(|| format!("{name} has no mac address"))?;
+    let mut bytes = [0u8; 6];
+    let mut octets = mac.split(':');
+    for byte in &mut bytes {
+        let octet = octets.next().with_context(|| format!("invalid mac address {mac}"))?;
+        *byte = u8::from_str_radix(octet, 16)?;
+    }
+    Ok(bytes)
+}
+
+fn format_mac(mac: [u8; 6]) -> String {
+    mac.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(":")
+}
+
+fn open_netns(netns: &Path) -> Result<File, anyhow::Error> {
+    File::open(netns).with_context(|| format!("failed to open {}", netns.display()))
+}
+
+fn gateway(network: &NetworkSpec) -> Ipv4Addr {
+    if network.gateway.is_unspecified() {
+        Ipv4Addr::from(u32::from(network.prefix) + 1)
+    } else {
+        network.gateway
+    }
+}
+
+fn parse_network(conf: &NetConf) -> Result<NetworkSpec, anyhow::Error> {
+    let network: NetworkSpec = conf.network.parse().map_err(|e: String| cni_error(ERR_INVALID_CONFIG, e))?;
+    if network.prefix_len > 30 {
+        return Err(cni_error(ERR_INVALID_CONFIG, format!("network {} is too small", conf.network)));
+    }
+    Ok(network)
+}
+
+// Takes the address an earlier ADD of the same container holds, or allocates
+// one, and wires the pod to it. A failed wire only undoes what this ADD did,
+// a pod an earlier ADD wired keeps its links and its address.
+fn setup<T>(
+    ipam: &Ipam,
+    args: &Args,
+    network: &NetworkSpec,
+    gateway: Ipv4Addr,
+    wire: impl FnOnce(Ipv4Addr) -> Result<T, anyhow::Error>,
+    unwire: impl FnOnce(),
+) -> Result<(Ipv4Addr, T), anyhow::Error> {
+    let existing = ipam.find(args)?;
+    let addr = match existing {
+        Some(addr) => addr,
+        None => ipam.allocate(args, network, gateway)?,
+    };
+    match wire(addr) {
+        Ok(wired) => Ok((addr, wired)),
+        Err(e) => {
+            if existing.is_none() {
+                unwire();
+                ipam.release(addr)?;
+            }
+            Err(e)
+        }
+    }
+}
+
+fn add(conf: &NetConf, args: &Args) -> Result<serde_json::Value, anyhow::Error> {
+    let netns = args.netns.as_deref().ok_or_else(|| cni_error(ERR_INVALID_ENV, "CNI_NETNS is not set"))?;
+    let network = parse_network(conf)?;
+    let gateway = gateway(&network);
+    let next_hop = get_interface_ip_address(&conf.phy)
+        .with_context(|| format!("{} has no ipv4 address", conf.phy))?;
+
+    let ipam = Ipam::open(conf)?;
+    let host_veth = args.host_veth();
+
+    let wire = |addr: Ipv4Addr| -> Result<([u8; 6], [u8; 6]), anyhow::Error> {
+        let cidr = format!("{}/{}", addr, network.prefix_len);
+        let pid = std::process::id().to_string();
+        let gateway = gateway.to_string();
+        let ns = open_netns(netns)?;
+        let mac = in_netns(Some(&ns), || {
+            ip(&["link", "add", &args.ifname, "type", "veth", "peer", "name", &host_veth, "netns", &pid])?;
+            if let Some(mtu) = conf.mtu {
+                ip(&["link", "set", "dev", &args.ifname, "mtu", &mtu.to_string()])?;
+            }
+            ip(&["addr", "add", &cidr, "dev", &args.ifname])?;
+            ip(&["link", "set", "dev", "lo", "up"])?;
+            ip(&["link", "set", "dev", &args.ifname, "up"])?;
+            ip(&["route", "add", "default", "via", &gateway, "dev", &args.ifname])?;
+            link_mac(&args.ifname)
+        })?;
+        if let Some(mtu) = conf.mtu {
+            ip(&["link", "set", "dev", &host_veth, "mtu", &mtu.to_string()])?;
+        }
+        ip(&["link", "set", "dev", &host_veth, "up"])?;
+        let mut endpoints = EndpointMaps::open_pinned(&conf.pin_path)?;
+        endpoints.add(u32::from(addr), Interface {
+            mac,
+            ifidx: get_interface_index(&host_veth)?,
+            next_hop,
+            flags: INTERFACE_FLAG_LOCAL,
+        })?;
+        Ok((mac, link_mac(&host_veth)?))
+    };
+    // Deleting the host end takes the pod end with it.
+    let unwire = || {
+        let _ = ip(&["link", "del", "dev", &host_veth]);
+    };
+    let (addr, (mac, host_mac)) = setup(&ipam, args, &network, gateway, wire, unwire)?;
+    Ok(add_result(conf, args, netns, addr, &network, gateway, (host_mac, mac)))
+}
+
+// The ADD result for the pod wired to `addr`, given the MACs of the host and
+// the pod end of its veth.
+fn add_result(
+    conf: &NetConf,
+    args: &Args,
+    netns: &Path,
+    addr: Ipv4Addr,
+    network: &NetworkSpec,
+    gateway: Ipv4Addr,
+    (host_mac, mac): ([u8; 6], [u8; 6]),
+) -> serde_json::Value {
+    let mut ip_config = json!({
+        "address": format!("{}/{}", addr, network.prefix_len),
+        "gateway": gateway.to_string(),
+        "interface": 1,
+    });
+    if conf.cni_version.starts_with("0.") {
+        ip_config["version"] = json!("4");
+    }
+    json!({
+        "cniVersion": conf.cni_version,
+        "interfaces": [
+            { "name": args.host_veth(), "mac": format_mac(host_mac) },
+            { "name": args.ifname, "mac": format_mac(mac), "sandbox": netns },
+        ],
+        "ips": [ip_config],
+        "routes": [{ "dst": "0.0.0.0/0", "gw": gateway.to_string() }],
+        "dns": {},
+    })
+}
+
+// DEL must succeed when parts of the pod are already gone.
+fn del(conf: &NetConf, args: &Args) -> Result<(), anyhow::Error> {
+    let ipam = Ipam::open(conf)?;
+    let _ = ip(&["link", "del", "dev", &args.host_veth()]);
+    let addr = match ipam.find(args)? {
+        Some(addr) => addr,
+        None => return Ok(()),
+    };
+    // Nothing to unregister if the sprayer never ran since boot.
+    if conf.pin_path.join("INTERFACE").exists() {
+        EndpointMaps::open_pinned(&conf.pin_path)?.remove(u32::from(addr))?;
+    }
+    ipam.release(addr)
+}
+
+fn check(conf: &NetConf, args: &Args) -> Result<(), anyhow::Error> {
+    let netns = args.netns.as_deref().ok_or_else(|| cni_error(ERR_INVALID_ENV, "CNI_NETNS is not set"))?;
+    let network = parse_network(conf)?;
+    let addr = Ipam::open(conf)?.find(args)?
+        .with_context(|| format!("no address allocated to {}", args.container_id))?;
+    let host_veth = args.host_veth();
+    let ifidx = get_interface_index(&host_veth).with_context(|| format!("{host_veth} is missing"))?;
+    let endpoints = EndpointMaps::open_pinned(&conf.pin_path)?;
+    match endpoints.get(u32::from(addr))? {
+        Some(intf) if intf.ifidx == ifidx => {}
+        Some(_) => bail!("{addr} is registered on another port"),
+        None => bail!("{addr} is not registered"),
+    }
+    let cidr = format!("{}/{}", addr, network.prefix_len);
+    let ns = open_netns(netns)?;
+    let addrs = in_netns(Some(&ns), || ip(&["-o", "addr", "show", "dev", &args.ifname]))?;
+    if !addrs.split_whitespace().any(|word| word == cidr) {
+        bail!("{} lacks {}", args.ifname, cidr);
+    }
+    Ok(())
+}
+
+fn read_conf() -> Result<NetConf, anyhow::Error> {
+    let mut stdin = String::new();
+    std::io::stdin().read_to_string(&mut stdin)?;
+    let conf: NetConf = serde_json::from_str(&stdin)
+        .map_err(|e| cni_error(ERR_DECODE, format!("invalid network configuration: {e}")))?;
+    if !SUPPORTED_VERSIONS.contains(&conf.cni_version.as_str()) {
+        return Err(cni_error(
+            ERR_INCOMPATIBLE_VERSION,
+            format!("unsupported CNI version {}", conf.cni_version),
+        ));
+    }
+    Ok(conf)
+}
+
+fn run(command: &str) -> Result<Option<serde_json::Value>, anyhow::Error> {
+    match command {
+        "ADD" => add(&read_conf()?, &Args::from_env()?).map(Some),
+        "DEL" => del(&read_conf()?, &Args::from_env()?).map(|_| None),
+        "CHECK" => check(&read_conf()?, &Args::from_env()?).map(|_| None),
+        "VERSION" => Ok(Some(json!({
+            "cniVersion": SUPPORTED_VERSIONS[SUPPORTED_VERSIONS.len() - 1],
+            "supportedVersions": SUPPORTED_VERSIONS,
+        }))),
+        _ => Err(cni_error(ERR_INVALID_ENV, format!("unknown CNI_COMMAND {command:?}"))),
+    }
+}
+
+fn error_result(e: &anyhow::Error) -> serde_json::Value {
+    let code = e.downcast_ref::<CniError>().map_or(ERR_INTERNAL, |e| e.code);
+    json!({
+        "cniVersion": SUPPORTED_VERSIONS[SUPPORTED_VERSIONS.len() - 1],
+        "code": code,
+        "msg": format!("{e:#}"),
+    })
+}
+
+fn main() {
+    let command = std::env::var("CNI_COMMAND").unwrap_or_default();
+    match run(&command) {
+        Ok(Some(result)) => println!("{result}"),
+        Ok(None) => {}
+        Err(e) => {
+            println!("{}", error_result(&e));
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(name: &str, version: &str) -> NetConf {
+        NetConf {
+            cni_version: version.to_string(),
+            name: name.to_string(),
+            network: "10.0.0.0/29,gw=10.0.0.1".to_string(),
+            phy: "eth0".to_string(),
+            pin_path: default_pin_path(),
+            data_dir: std::env::temp_dir().join(format!("sprayer-cni-{}-{}", std::process::id(), name)),
+            mtu: None,
+        }
+    }
+
+    fn args(container_id: &str) -> Args {
+        Args {
+            container_id: container_id.to_string(),
+            netns: Some(PathBuf::from("/var/run/netns/pod")),
+            ifname: "eth0".to_string(),
+        }
+    }
+
+    fn code(e: &anyhow::Error) -> Option<u32> {
+        e.downcast_ref::<CniError>().map(|e| e.code)
+    }
+
+    #[test]
+    fn args_from_vars() {
+        let vars = |netns: &'static str| move |name: &str| match name {
+            "CNI_CONTAINERID" => Some("abc".to_string()),
+            "CNI_NETNS" => Some(netns.to_string()),
+            "CNI_IFNAME" => Some("eth0".to_string()),
+            _ => None,
+        };
+        let args = Args::from_vars(vars("/var/run/netns/pod")).unwrap();
+        assert_eq!(args.container_id, "abc");
+        assert_eq!(args.netns, Some(PathBuf::from("/var/run/netns/pod")));
+        // DEL may come without a namespace.
+        assert_eq!(Args::from_vars(vars("")).unwrap().netns, None);
+        let missing = Args::from_vars(|name| (name != "CNI_IFNAME").then(|| "x".to_string()));
+        assert_eq!(code(&missing.err().unwrap()), Some(ERR_INVALID_ENV));
+    }
+
+    #[test]
+    fn host_veth_names() {
+        let veth = args("abc").host_veth();
+        assert!(veth.starts_with("spr") && veth.len() <= 15, "{veth}");
+        assert_eq!(veth, args("abc").host_veth());
+        assert_ne!(veth, args("abd").host_veth());
+        let mut other_if = args("abc");
+        other_if.ifname = "net1".to_string();
+        assert_ne!(veth, other_if.host_veth());
+    }
+
+    #[test]
+    fn network_checked() {
+        let mut conf = conf("network", "1.0.0");
+        let network = parse_network(&conf).unwrap();
+        assert_eq!(gateway(&network), Ipv4Addr::new(10, 0, 0, 1));
+        conf.network = "10.0.0.0/31".to_string();
+        assert_eq!(code(&parse_network(&conf).err().unwrap()), Some(ERR_INVALID_CONFIG));
+        conf.network = "10.0.0.0".to_string();
+        assert_eq!(code(&parse_network(&conf).err().unwrap()), Some(ERR_INVALID_CONFIG));
+    }
+
+    #[test]
+    fn ipam_allocates_and_releases() {
+        let conf = conf("ipam", "1.0.0");
+        let network = parse_network(&conf).unwrap();
+        let ipam = Ipam::open(&conf).unwrap();
+        let gateway = gateway(&network);
+        // The /29 has .1 to .6, .1 is the gateway.
+        let mut addrs = Vec::new();
+        for i in 0..5 {
+            let args = args(&format!("pod{i}"));
+            let addr = ipam.allocate(&args, &network, gateway).unwrap();
+            assert_eq!(ipam.find(&args).unwrap(), Some(addr));
+            addrs.push(addr);
+        }
+        assert_eq!(addrs.first(), Some(&Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(addrs.last(), Some(&Ipv4Addr::new(10, 0, 0, 6)));
+        assert!(ipam.allocate(&args("pod5"), &network, gateway).is_err());
+        ipam.release(addrs[2]).unwrap();
+        assert_eq!(ipam.find(&args("pod2")).unwrap(), None);
+        assert_eq!(ipam.allocate(&args("pod5"), &network, gateway).unwrap(), addrs[2]);
+        // Releasing twice is fine, DEL may be repeated.
+        ipam.release(addrs[2]).unwrap();
+        ipam.release(addrs[2]).unwrap();
+        std::fs::remove_dir_all(&conf.data_dir).unwrap();
+    }
+
+    #[test]
+    fn setup_undoes_failed_add() {
+        let conf = conf("failed", "1.0.0");
+        let network = parse_network(&conf).unwrap();
+        let ipam = Ipam::open(&conf).unwrap();
+        let gateway = gateway(&network);
+        let mut unwired = false;
+        let res = setup(&ipam, &args("pod"), &network, gateway, |_| -> Result<(), _> { bail!("wire failed") }, || unwired = true);
+        assert!(res.is_err());
+        assert!(unwired);
+        assert_eq!(ipam.find(&args("pod")).unwrap(), None);
+        std::fs::remove_dir_all(&conf.data_dir).unwrap();
+    }
+
+    #[test]
+    fn setup_keeps_earlier_add() {
+        let conf = conf("repeated", "1.0.0");
+        let network = parse_network(&conf).unwrap();
+        let ipam = Ipam::open(&conf).unwrap();
+        let gateway = gateway(&network);
+        let (addr, ()) = setup(&ipam, &args("pod"), &network, gateway, |_| Ok(()), || unreachable!()).unwrap();
+        // A repeated ADD of the same container gets the same address, and
+        // failing to wire it again leaves the pod alone.
+        let (again, wired) = setup(&ipam, &args("pod"), &network, gateway, Ok, || unreachable!()).unwrap();
+        assert_eq!((again, wired), (addr, addr));
+        let mut unwired = false;
+        let res = setup(&ipam, &args("pod"), &network, gateway, |_| -> Result<(), _> { bail!("exists") }, || unwired = true);
+        assert!(res.is_err());
+        assert!(!unwired);
+        assert_eq!(ipam.find(&args("pod")).unwrap(), Some(addr));
+        std::fs::remove_dir_all(&conf.data_dir).unwrap();
+    }
+
+    #[test]
+    fn add_and_check_need_netns() {
+        let conf = conf("netns", "1.0.0");
+        let mut args = args("pod");
+        args.netns = None;
+        assert_eq!(code(&add(&conf, &args).err().unwrap()), Some(ERR_INVALID_ENV));
+        assert_eq!(code(&check(&conf, &args).err().unwrap()), Some(ERR_INVALID_ENV));
+    }
+
+    #[test]
+    fn del_unknown_pod() {
+        let conf = conf("del", "1.0.0");
+        del(&conf, &args("pod")).unwrap();
+        std::fs::remove_dir_all(&conf.data_dir).unwrap();
+    }
+
+    #[test]
+    fn add_result_versions() {
+        let network = parse_network(&conf("result", "1.0.0")).unwrap();
+        let macs = ([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2]);
+        let addr = Ipv4Addr::new(10, 0, 0, 2);
+        let gateway = gateway(&network);
+        let netns = Path::new("/var/run/netns/pod");
+        let args = args("pod");
+
+        let result = add_result(&conf("result", "1.0.0"), &args, netns, addr, &network, gateway, macs);
+        assert_eq!(result["cniVersion"], "1.0.0");
+        assert_eq!(result["interfaces"][0]["name"], args.host_veth());
+        assert_eq!(result["interfaces"][0]["mac"], "02:00:00:00:00:01");
+        assert_eq!(result["interfaces"][1]["mac"], "02:00:00:00:00:02");
+        assert_eq!(result["interfaces"][1]["sandbox"], "/var/run/netns/pod");
+        assert_eq!(result["ips"][0]["address"], "10.0.0.2/29");
+        assert_eq!(result["ips"][0]["gateway"], "10.0.0.1");
+        assert!(result["ips"][0].get("version").is_none());
+
+        let result = add_result(&conf("result", "0.4.0"), &args, netns, addr, &network, gateway, macs);
+        assert_eq!(result["ips"][0]["version"], "4");
+    }
+
+    #[test]
+    fn commands() {
+        let version = run("VERSION").unwrap().unwrap();
+        assert_eq!(version["supportedVersions"].as_array().unwrap().len(), SUPPORTED_VERSIONS.len());
+        let e = run("UPGRADE").err().unwrap();
+        assert_eq!(error_result(&e)["code"], ERR_INVALID_ENV);
+        assert_eq!(error_result(&anyhow::anyhow!("boom"))["code"], ERR_INTERNAL);
+    }
+}
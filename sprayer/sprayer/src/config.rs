@@ -0,0 +1,439 @@
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use aya::maps::lpm_trie::Key;
+use common::{
+    Network, SprayExceptionKey, NETWORK_FLAG_DSCP, NETWORK_FLAG_ENCRYPT, NETWORK_FLAG_FIB_DIRECT,
+    NETWORK_FLAG_ID_COUNTER, OUTER_DF_CLEAR, OUTER_DF_INHERIT, OUTER_DF_SET,
+    SPRAY_FLOWLET, SPRAY_HASH, SPRAY_NONE, SPRAY_RANDOM, SPRAY_ROUND_ROBIN, DEFAULT_FLOWLET_GAP_US,
+};
+
+/// An overlay network as given on the command line, a prefix followed by
+/// comma separated options, e.g.
+/// `10.0.0.0/24,gw=10.0.0.1,encrypt,dscp=46,spray=flowlet,flowlet-gap=500`.
+/// The spray policies are `random`, `round-robin`, `flowlet`, `hash` and `none`.
+/// `except=PROTO[:PORT]=POLICY`, which may be repeated, sprays some of the
+/// network's traffic differently.
+/// `table=N`, `direct` and `src=IP` select the underlay routing of the
+/// network's tunnels, `ttl=N|inherit`, `df=set|clear|inherit` and
+/// `id=counter|inherit` how their outer IP headers are filled in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NetworkSpec {
+    pub prefix: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Ipv4Addr,
+    pub encrypt: bool,
+    /// DSCP for outer headers, otherwise the inner one is copied.
+    pub dscp: Option<u8>,
+    pub spray: u32,
+    pub exceptions: Vec<SprayException>,
+    /// Pause in microseconds after which a flowlet may change paths.
+    pub flowlet_gap: u32,
+    /// Underlay routing table, implies a direct lookup.
+    pub table: Option<u32>,
+    /// Skip policy rules when looking up the underlay next hop.
+    pub direct: bool,
+    /// Outer source address instead of the physical interface's.
+    pub src: Option<Ipv4Addr>,
+    /// Outer TTL, otherwise the inner one is copied.
+    pub ttl: Option<u8>,
+    /// One of the `OUTER_DF_*` policies.
+    pub df: u32,
+    /// Number outer headers ourselves instead of copying the inner ID.
+    pub id_counter: bool,
+}
+
+impl NetworkSpec {
+    pub fn key(&self) -> Key<u32> {
+        // LPM trie keys are matched byte by byte, so the prefix stays in
+        // network byte order.
+        Key::new(self.prefix_len as u32, u32::from_ne_bytes(self.prefix.octets()))
+    }
+
+    /// Whether `ip`, in host byte order, lies within the network.
+    pub fn contains(&self, ip: u32) -> bool {
+        ip & mask(self.prefix_len) == u32::from(self.prefix)
+    }
+
+    pub fn value(&self) -> Network {
+        let mut flags = 0;
+        if self.encrypt {
+            flags |= NETWORK_FLAG_ENCRYPT;
+        }
+        if self.dscp.is_some() {
+            flags |= NETWORK_FLAG_DSCP;
+        }
+        // The kernel only honours the table in direct lookups.
+        if self.direct || self.table.is_some() {
+            flags |= NETWORK_FLAG_FIB_DIRECT;
+        }
+        if self.id_counter {
+            flags |= NETWORK_FLAG_ID_COUNTER;
+        }
+        Network {
+            gateway: u32::from_be_bytes(self.gateway.octets()),
+            flags,
+            dscp: self.dscp.unwrap_or_default() as u32,
+            spray: self.spray,
+            flowlet_gap: self.flowlet_gap,
+            fib_table: self.table.unwrap_or_default(),
+            src: self.src.map(u32::from).unwrap_or_default(),
+            ttl: self.ttl.unwrap_or_default() as u32,
+            df: self.df,
+            prefix: u32::from(self.prefix),
+            prefix_len: self.prefix_len as u32,
+        }
+    }
+
+    /// SPRAYEXCEPT entries for the network's exceptions.
+    pub fn exception_entries(&self) -> impl Iterator<Item = (SprayExceptionKey, u32)> + '_ {
+        self.exceptions.iter().map(move |exception| {
+            let key = SprayExceptionKey {
+                prefix: u32::from(self.prefix),
+                prefix_len: self.prefix_len,
+                ip_proto: exception.ip_proto,
+                port: exception.port,
+            };
+            (key, exception.policy)
+        })
+    }
+}
+
+impl FromStr for NetworkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut opts = s.split(',');
+        let cidr = opts.next().unwrap_or_default();
+        let (prefix, prefix_len) = cidr
+            .split_once('/')
+            .ok_or_else(|| format!("invalid network {cidr}, expected prefix/len"))?;
+        let prefix: Ipv4Addr = prefix.parse().map_err(|e| format!("invalid prefix {prefix}: {e}"))?;
+        let prefix_len: u8 = match prefix_len.parse() {
+            Ok(len) if len <= 32 => len,
+            _ => return Err(format!("invalid prefix length {prefix_len}")),
+        };
+        let mut spec = NetworkSpec {
+            prefix: Ipv4Addr::from(u32::from(prefix) & mask(prefix_len)),
+            prefix_len,
+            gateway: Ipv4Addr::UNSPECIFIED,
+            encrypt: false,
+            dscp: None,
+            spray: SPRAY_RANDOM,
+            exceptions: Vec::new(),
+            flowlet_gap: DEFAULT_FLOWLET_GAP_US,
+            table: None,
+            direct: false,
+            src: None,
+            ttl: None,
+            df: OUTER_DF_INHERIT,
+            id_counter: false,
+        };
+        for opt in opts {
+            match opt.split_once('=') {
+                Some(("gw", gw)) => {
+                    spec.gateway = gw.parse().map_err(|e| format!("invalid gateway {gw}: {e}"))?;
+                }
+                Some(("dscp", dscp)) => match dscp.parse() {
+                    Ok(dscp) if dscp < 64 => spec.dscp = Some(dscp),
+                    _ => return Err(format!("invalid dscp {dscp}")),
+                },
+                Some(("spray", spray)) => spec.spray = parse_spray_policy(spray)?,
+                Some(("except", exception)) => spec.exceptions.push(exception.parse()?),
+                Some(("flowlet-gap", gap)) => {
+                    spec.flowlet_gap = gap.parse().map_err(|e| format!("invalid flowlet gap {gap}: {e}"))?;
+                }
+                Some(("table", table)) => match table.parse() {
+                    Ok(table) if table != 0 => spec.table = Some(table),
+                    _ => return Err(format!("invalid table {table}")),
+                },
+                Some(("src", src)) => {
+                    spec.src = Some(src.parse().map_err(|e| format!("invalid source {src}: {e}"))?);
+                }
+                Some(("ttl", "inherit")) => spec.ttl = None,
+                Some(("ttl", ttl)) => match ttl.parse() {
+                    Ok(ttl) if ttl != 0 => spec.ttl = Some(ttl),
+                    _ => return Err(format!("invalid ttl {ttl}")),
+                },
+                Some(("df", df)) => {
+                    spec.df = match df {
+                        "inherit" => OUTER_DF_INHERIT,
+                        "set" => OUTER_DF_SET,
+                        "clear" => OUTER_DF_CLEAR,
+                        _ => return Err(format!("invalid df policy {df}")),
+                    };
+                }
+                Some(("id", "counter")) => spec.id_counter = true,
+                Some(("id", "inherit")) => spec.id_counter = false,
+                None if opt == "encrypt" => spec.encrypt = true,
+                None if opt == "direct" => spec.direct = true,
+                _ => return Err(format!("unknown network option {opt}")),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// An overlay address behind a remote sprayer, `OVERLAY@UNDERLAY`, e.g.
+/// `10.0.0.2@192.168.0.2`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteEndpoint {
+    pub ip: Ipv4Addr,
+    pub underlay: Ipv4Addr,
+}
+
+impl FromStr for RemoteEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, underlay) = s
+            .split_once('@')
+            .ok_or_else(|| format!("invalid remote endpoint {s}, expected overlay@underlay"))?;
+        Ok(RemoteEndpoint {
+            ip: ip.parse().map_err(|e| format!("invalid overlay address {ip}: {e}"))?,
+            underlay: underlay.parse().map_err(|e| format!("invalid underlay address {underlay}: {e}"))?,
+        })
+    }
+}
+
+fn mask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+/// Parses a MAC address written as six colon separated hex octets, in the
+/// order they go on the wire.
+pub fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let mut mac = [0u8; 6];
+    let mut octets = s.split(':');
+    for octet in mac.iter_mut() {
+        let hex = octets.next().ok_or_else(|| format!("invalid MAC address {s}, expected 6 octets"))?;
+        if hex.is_empty() || hex.len() > 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("invalid octet {hex} in MAC address {s}"));
+        }
+        *octet = u8::from_str_radix(hex, 16).map_err(|e| format!("invalid octet {hex}: {e}"))?;
+    }
+    if octets.next().is_some() {
+        return Err(format!("invalid MAC address {s}, expected 6 octets"));
+    }
+    Ok(mac)
+}
+
+fn parse_spray_policy(s: &str) -> Result<u32, String> {
+    match s {
+        "random" => Ok(SPRAY_RANDOM),
+        "round-robin" => Ok(SPRAY_ROUND_ROBIN),
+        "flowlet" => Ok(SPRAY_FLOWLET),
+        "hash" => Ok(SPRAY_HASH),
+        "none" => Ok(SPRAY_NONE),
+        _ => Err(format!("unknown spray policy {s}")),
+    }
+}
+
+/// A spray policy overriding the network's for some traffic, e.g.
+/// `udp:5004=none` or `tcp=hash`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SprayException {
+    pub ip_proto: u8,
+    pub port: u16,
+    pub policy: u32,
+}
+
+impl FromStr for SprayException {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (traffic, policy) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid spray exception {s}, expected proto[:port]=policy"))?;
+        let (proto, port) = match traffic.split_once(':') {
+            Some((proto, port)) => (proto, port.parse().map_err(|e| format!("invalid port {port}: {e}"))?),
+            None => (traffic, 0),
+        };
+        let ip_proto = match proto {
+            "tcp" => 6,
+            "udp" => 17,
+            proto => proto.parse().map_err(|_| format!("unknown protocol {proto}"))?,
+        };
+        Ok(SprayException {
+            ip_proto,
+            port,
+            policy: parse_spray_policy(policy)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_defaults() {
+        let spec: NetworkSpec = "10.0.0.0/24".parse().unwrap();
+        assert_eq!(spec.prefix, Ipv4Addr::new(10, 0, 0, 0));
+        assert_eq!(spec.prefix_len, 24);
+        assert_eq!(spec.gateway, Ipv4Addr::UNSPECIFIED);
+        assert!(!spec.encrypt);
+        assert_eq!(spec.dscp, None);
+        assert_eq!(spec.spray, SPRAY_RANDOM);
+        assert!(spec.exceptions.is_empty());
+        assert_eq!(spec.flowlet_gap, DEFAULT_FLOWLET_GAP_US);
+        assert_eq!(spec.table, None);
+        assert!(!spec.direct);
+        assert_eq!(spec.src, None);
+        assert_eq!(spec.ttl, None);
+        assert_eq!(spec.df, OUTER_DF_INHERIT);
+        assert!(!spec.id_counter);
+    }
+
+    #[test]
+    fn network_masks_prefix() {
+        let spec: NetworkSpec = "10.0.0.77/24".parse().unwrap();
+        assert_eq!(spec.prefix, Ipv4Addr::new(10, 0, 0, 0));
+        let spec: NetworkSpec = "10.0.0.77/0".parse().unwrap();
+        assert_eq!(spec.prefix, Ipv4Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn network_contains() {
+        let spec: NetworkSpec = "10.0.0.0/24".parse().unwrap();
+        assert!(spec.contains(u32::from(Ipv4Addr::new(10, 0, 0, 7))));
+        assert!(!spec.contains(u32::from(Ipv4Addr::new(10, 0, 1, 7))));
+        let all: NetworkSpec = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(u32::from(Ipv4Addr::new(192, 168, 0, 1))));
+    }
+
+    #[test]
+    fn network_options() {
+        let spec: NetworkSpec = "10.0.0.0/24,gw=10.0.0.1,encrypt,dscp=46,spray=flowlet,flowlet-gap=200,\
+            table=100,direct,src=192.168.0.9,ttl=32,df=set,id=counter"
+            .parse()
+            .unwrap();
+        assert_eq!(spec.gateway, Ipv4Addr::new(10, 0, 0, 1));
+        assert!(spec.encrypt);
+        assert_eq!(spec.dscp, Some(46));
+        assert_eq!(spec.spray, SPRAY_FLOWLET);
+        assert_eq!(spec.flowlet_gap, 200);
+        assert_eq!(spec.table, Some(100));
+        assert!(spec.direct);
+        assert_eq!(spec.src, Some(Ipv4Addr::new(192, 168, 0, 9)));
+        assert_eq!(spec.ttl, Some(32));
+        assert_eq!(spec.df, OUTER_DF_SET);
+        assert!(spec.id_counter);
+
+        let value = spec.value();
+        assert_eq!(value.gateway, u32::from(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(
+            value.flags,
+            NETWORK_FLAG_ENCRYPT | NETWORK_FLAG_DSCP | NETWORK_FLAG_FIB_DIRECT | NETWORK_FLAG_ID_COUNTER
+        );
+        assert_eq!(value.dscp, 46);
+        assert_eq!(value.fib_table, 100);
+        assert_eq!(value.src, u32::from(Ipv4Addr::new(192, 168, 0, 9)));
+        assert_eq!(value.ttl, 32);
+        assert_eq!(value.prefix, u32::from(Ipv4Addr::new(10, 0, 0, 0)));
+        assert_eq!(value.prefix_len, 24);
+    }
+
+    #[test]
+    fn network_table_implies_direct() {
+        let spec: NetworkSpec = "10.0.0.0/24,table=7".parse().unwrap();
+        assert_eq!(spec.value().flags, NETWORK_FLAG_FIB_DIRECT);
+    }
+
+    #[test]
+    fn network_inherit_options() {
+        let spec: NetworkSpec = "10.0.0.0/24,ttl=9,ttl=inherit,id=counter,id=inherit,df=clear".parse().unwrap();
+        assert_eq!(spec.ttl, None);
+        assert!(!spec.id_counter);
+        assert_eq!(spec.df, OUTER_DF_CLEAR);
+    }
+
+    #[test]
+    fn network_spray_policies() {
+        for (name, policy) in [
+            ("random", SPRAY_RANDOM),
+            ("round-robin", SPRAY_ROUND_ROBIN),
+            ("flowlet", SPRAY_FLOWLET),
+            ("hash", SPRAY_HASH),
+            ("none", SPRAY_NONE),
+        ] {
+            let spec: NetworkSpec = format!("10.0.0.0/24,spray={name}").parse().unwrap();
+            assert_eq!(spec.spray, policy);
+        }
+    }
+
+    #[test]
+    fn network_invalid() {
+        for s in [
+            "10.0.0.0",
+            "10.0.0/24",
+            "10.0.0.0/33",
+            "10.0.0.0/24,gw=10.0.0",
+            "10.0.0.0/24,dscp=64",
+            "10.0.0.0/24,spray=sometimes",
+            "10.0.0.0/24,flowlet-gap=-1",
+            "10.0.0.0/24,table=0",
+            "10.0.0.0/24,src=host",
+            "10.0.0.0/24,ttl=0",
+            "10.0.0.0/24,ttl=256",
+            "10.0.0.0/24,df=maybe",
+            "10.0.0.0/24,id=random",
+            "10.0.0.0/24,except=udp",
+            "10.0.0.0/24,fast",
+        ] {
+            assert!(s.parse::<NetworkSpec>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn network_exceptions() {
+        let spec: NetworkSpec = "10.0.0.0/24,spray=random,except=udp:5004=none,except=tcp=hash".parse().unwrap();
+        assert_eq!(spec.exceptions, vec![
+            SprayException { ip_proto: 17, port: 5004, policy: SPRAY_NONE },
+            SprayException { ip_proto: 6, port: 0, policy: SPRAY_HASH },
+        ]);
+        let entries: Vec<(SprayExceptionKey, u32)> = spec.exception_entries().collect();
+        assert_eq!(entries.len(), 2);
+        let (key, policy) = entries[0];
+        assert_eq!(key.prefix, u32::from(Ipv4Addr::new(10, 0, 0, 0)));
+        assert_eq!(key.prefix_len, 24);
+        assert_eq!((key.ip_proto, key.port, policy), (17, 5004, SPRAY_NONE));
+    }
+
+    #[test]
+    fn spray_exception() {
+        let exception: SprayException = "udp:5004=none".parse().unwrap();
+        assert_eq!(exception, SprayException { ip_proto: 17, port: 5004, policy: SPRAY_NONE });
+        let exception: SprayException = "tcp=round-robin".parse().unwrap();
+        assert_eq!(exception, SprayException { ip_proto: 6, port: 0, policy: SPRAY_ROUND_ROBIN });
+        let exception: SprayException = "132:36412=hash".parse().unwrap();
+        assert_eq!(exception, SprayException { ip_proto: 132, port: 36412, policy: SPRAY_HASH });
+    }
+
+    #[test]
+    fn spray_exception_invalid() {
+        for s in ["udp", "udp:5004", "udp:port=none", "udp:70000=none", "sctp=none", "256=none", "udp=sometimes"] {
+            assert!(s.parse::<SprayException>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn mac() {
+        assert_eq!(parse_mac("de:ad:be:ef:ba:be"), Ok([0xde, 0xad, 0xbe, 0xef, 0xba, 0xbe]));
+        assert_eq!(parse_mac("2:0:0:0:0:A"), Ok([2, 0, 0, 0, 0, 10]));
+        for s in ["", "de:ad:be:ef:ba", "de:ad:be:ef:ba:be:00", "de:ad:be:ef:ba:bg", "de:ad:be:ef::be", "de:ad:be:ef:ba:+e", "de:ad:be:ef:ba:bee"] {
+            assert!(parse_mac(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn remote_endpoint() {
+        let remote: RemoteEndpoint = "10.0.0.2@192.168.0.2".parse().unwrap();
+        assert_eq!(remote, RemoteEndpoint {
+            ip: Ipv4Addr::new(10, 0, 0, 2),
+            underlay: Ipv4Addr::new(192, 168, 0, 2),
+        });
+        assert!("10.0.0.2".parse::<RemoteEndpoint>().is_err());
+        assert!("10.0.0.2@underlay".parse::<RemoteEndpoint>().is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::borrow::{Borrow, BorrowMut};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use aya::maps::{self, MapData, MapError};
+use common::{PathKey, PathStats, PathWeights, MAX_PATHS};
+use log::{debug, info, warn};
+use tokio::sync::mpsc::Sender;
+
+// A report is the magic followed by (path, level) byte pairs, the level being
+// the share of CE marked packets on the path scaled to 255.
+const MAGIC: &[u8; 4] = b"SPCF";
+const MAX_PACKET: usize = MAGIC.len() + 2 * MAX_PATHS;
+
+// Paths keep a minimum share so they are still measured and can recover.
+const MIN_WEIGHT: u8 = 16;
+// Remotes that stopped reporting are sprayed evenly again.
+const FEEDBACK_TIMEOUT: Duration = Duration::from_secs(10);
+// Remotes report once a second. Reports arriving faster and congestion moving
+// further than this per report are not taken, so a flood of reports cannot
+// starve a path at once.
+const MIN_REPORT_GAP: Duration = Duration::from_millis(500);
+const MAX_STEP: u8 = 32;
+
+/// Congestion levels a remote measured on the paths we send it.
+#[derive(Debug)]
+pub struct Feedback {
+    pub remote: Ipv4Addr,
+    pub levels: Vec<(u32, u8)>,
+}
+
+/// Turns the decap path counters into reports for the sending sprayers.
+pub struct Reporter {
+    sock: UdpSocket,
+    port: u16,
+    last: HashMap<PathKey, PathStats>,
+}
+
+impl Reporter {
+    /// Reports leave from `addr`, the underlay address remotes know us by,
+    /// so they match it with the paths they spray towards us.
+    pub fn new(addr: Ipv4Addr, port: u16) -> Result<Self, std::io::Error> {
+        Ok(Reporter {
+            sock: UdpSocket::bind((addr, 0))?,
+            port,
+            last: HashMap::new(),
+        })
+    }
+
+    /// Sends every remote the CE share of each path since the last report.
+    pub fn report<T: Borrow<MapData>>(
+        &mut self,
+        stats: &maps::HashMap<T, PathKey, PathStats>,
+    ) -> Result<(), MapError> {
+        let mut reports: HashMap<u32, Vec<u8>> = HashMap::new();
+        let mut current = HashMap::new();
+        for entry in stats.iter() {
+            let (key, now) = entry?;
+            let before = self.last.get(&key).copied().unwrap_or_default();
+            current.insert(key, now);
+            let Some(level) = level(before, now) else {
+                continue;
+            };
+            let report = reports.entry(key.remote).or_insert_with(|| MAGIC.to_vec());
+            report.extend_from_slice(&[key.path as u8, level]);
+        }
+        self.last = current;
+        for (remote, report) in reports {
+            let to = SocketAddrV4::new(Ipv4Addr::from(remote), self.port);
+            if let Err(e) = self.sock.send_to(&report, to) {
+                warn!("failed to send congestion report to {}: {}", to, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+// The CE share of the packets counted between `before` and `now`, scaled to
+// 255, or None if the path carried nothing.
+fn level(before: PathStats, now: PathStats) -> Option<u8> {
+    // Counters restart when the LRU evicted the path in between.
+    let (packets, ce) = if now.packets >= before.packets {
+        (now.packets - before.packets, now.ce.saturating_sub(before.ce))
+    } else {
+        (now.packets, now.ce)
+    };
+    if packets == 0 {
+        return None;
+    }
+    Some((ce.min(packets) * 255 / packets) as u8)
+}
+
+/// Receives reports from remote sprayers until the channel closes.
+pub async fn listen(addr: Ipv4Addr, port: u16, tx: Sender<Feedback>) -> Result<(), std::io::Error> {
+    let sock = tokio::net::UdpSocket::bind((addr, port)).await?;
+    let mut buf = [0u8; MAX_PACKET];
+    loop {
+        let (len, from) = sock.recv_from(&mut buf).await?;
+        let remote = match from.ip() {
+            std::net::IpAddr::V4(ip) => ip,
+            _ => continue,
+        };
+        let payload = match buf[..len].strip_prefix(MAGIC) {
+            Some(payload) if payload.len() % 2 == 0 => payload,
+            _ => {
+                debug!("ignoring malformed congestion report from {}", from);
+                continue;
+            }
+        };
+        let levels = payload
+            .chunks(2)
+            .filter(|pair| (pair[0] as usize) < MAX_PATHS)
+            .map(|pair| (pair[0] as u32, pair[1]))
+            .collect();
+        if tx.send(Feedback { remote, levels }).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Smoothed congestion per remote and path, programmed into PATHWEIGHT.
+#[derive(Default)]
+pub struct Weights {
+    remotes: HashMap<u32, ([u8; MAX_PATHS], Instant)>,
+}
+
+impl Weights {
+    pub fn apply<T: BorrowMut<MapData>>(
+        &mut self,
+        map: &mut maps::HashMap<T, u32, PathWeights>,
+        feedback: Feedback,
+    ) -> Result<(), MapError> {
+        let remote = u32::from(feedback.remote);
+        match self.update(remote, &feedback.levels, Instant::now()) {
+            Some(weights) => map.insert(remote, weights, 0),
+            None => {
+                debug!("ignoring early congestion report from {}", feedback.remote);
+                Ok(())
+            }
+        }
+    }
+
+    // Folds the levels into the remote's smoothed congestion and returns
+    // the weights that follow from it, or None if the report came too soon.
+    fn update(&mut self, remote: u32, levels: &[(u32, u8)], now: Instant) -> Option<PathWeights> {
+        if let Some((_, seen)) = self.remotes.get(&remote) {
+            if now.saturating_duration_since(*seen) < MIN_REPORT_GAP {
+                return None;
+            }
+        }
+        let (congestion, seen) = self.remotes.entry(remote).or_insert(([0; MAX_PATHS], now));
+        *seen = now;
+        for &(path, level) in levels {
+            let smoothed = &mut congestion[path as usize];
+            let target = ((*smoothed as u32 * 3 + level as u32) / 4) as u8;
+            *smoothed = target.clamp(smoothed.saturating_sub(MAX_STEP), smoothed.saturating_add(MAX_STEP));
+        }
+        let mut weights = PathWeights { weights: [0; MAX_PATHS] };
+        for (weight, congestion) in weights.weights.iter_mut().zip(congestion.iter()) {
+            *weight = (255 - *congestion).max(MIN_WEIGHT);
+        }
+        Some(weights)
+    }
+
+    pub fn expire<T: BorrowMut<MapData>>(&mut self, map: &mut maps::HashMap<T, u32, PathWeights>) {
+        self.remotes.retain(|remote, (_, seen)| {
+            if seen.elapsed() < FEEDBACK_TIMEOUT {
+                return true;
+            }
+            info!("no congestion feedback from {}, spraying evenly", Ipv4Addr::from(*remote));
+            // Already gone if the map was never programmed.
+            let _ = map.remove(remote);
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(packets: u64, ce: u64) -> PathStats {
+        PathStats { packets, ce }
+    }
+
+    #[test]
+    fn level_share_of_marked() {
+        assert_eq!(level(stats(0, 0), stats(100, 0)), Some(0));
+        assert_eq!(level(stats(0, 0), stats(100, 100)), Some(255));
+        assert_eq!(level(stats(100, 10), stats(200, 60)), Some(127));
+    }
+
+    #[test]
+    fn level_idle_path() {
+        assert_eq!(level(stats(100, 10), stats(100, 10)), None);
+        assert_eq!(level(PathStats::default(), PathStats::default()), None);
+    }
+
+    #[test]
+    fn level_counters_restarted() {
+        // The path was evicted and counted again from zero.
+        assert_eq!(level(stats(1000, 500), stats(10, 5)), Some(127));
+    }
+
+    #[test]
+    fn level_more_marked_than_counted() {
+        assert_eq!(level(stats(0, 0), stats(10, 20)), Some(255));
+    }
+
+    // Reports from `remote`, a second apart.
+    fn reports(weights: &mut Weights, remote: u32, reports: &[&[(u32, u8)]]) -> [u8; MAX_PATHS] {
+        let start = Instant::now();
+        let mut last = [0; MAX_PATHS];
+        for (i, levels) in reports.iter().enumerate() {
+            let now = start + Duration::from_secs(i as u64);
+            last = weights.update(remote, levels, now).unwrap().weights;
+        }
+        last
+    }
+
+    #[test]
+    fn weights_start_even() {
+        let mut weights = Weights::default();
+        assert_eq!(reports(&mut weights, 1, &[&[]]), [255; MAX_PATHS]);
+    }
+
+    #[test]
+    fn weights_smooth_congestion() {
+        let mut weights = Weights::default();
+        let first = reports(&mut weights, 1, &[&[(0, 100)]]);
+        assert_eq!(first[0], 255 - 25);
+        assert_eq!(first[1], 255);
+        let mut weights = Weights::default();
+        let second = reports(&mut weights, 1, &[&[(0, 100)], &[(0, 100)]]);
+        assert_eq!(second[0], 255 - 43);
+        let mut weights = Weights::default();
+        let recovered = reports(&mut weights, 1, &[&[(0, 100)], &[(0, 100)], &[(0, 0)]]);
+        assert!(recovered[0] > second[0]);
+    }
+
+    #[test]
+    fn weights_step_capped() {
+        let mut weights = Weights::default();
+        let first = reports(&mut weights, 1, &[&[(0, 255)]]);
+        assert_eq!(first[0], 255 - MAX_STEP);
+        let high = reports(&mut weights, 2, &[&[(0, 255)][..]; 8]);
+        let later = Instant::now() + Duration::from_secs(60);
+        let back = weights.update(2, &[(0, 0)], later).unwrap();
+        assert_eq!(back.weights[0], high[0] + MAX_STEP);
+    }
+
+    #[test]
+    fn weights_ignore_early_reports() {
+        let mut weights = Weights::default();
+        let now = Instant::now();
+        assert!(weights.update(1, &[(0, 255)], now).is_some());
+        assert!(weights.update(1, &[(0, 255)], now + MIN_REPORT_GAP / 2).is_none());
+        assert!(weights.update(2, &[(0, 255)], now + MIN_REPORT_GAP / 2).is_some());
+        let later = weights.update(1, &[(0, 255)], now + MIN_REPORT_GAP).unwrap();
+        assert_eq!(later.weights[0], 255 - 2 * MAX_STEP);
+    }
+
+    #[test]
+    fn weights_keep_minimum() {
+        let mut weights = Weights::default();
+        let last = reports(&mut weights, 1, &[&[(3, 255)][..]; 32]);
+        assert_eq!(last[3], MIN_WEIGHT);
+        assert_eq!(last[2], 255);
+    }
+
+    #[test]
+    fn weights_per_remote() {
+        let mut weights = Weights::default();
+        reports(&mut weights, 1, &[&[(0, 255)]]);
+        assert_eq!(reports(&mut weights, 2, &[&[]])[0], 255);
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use common::{Interface, INTERFACE_FLAG_LOCAL};
+use futures::stream::{StreamExt, TryStreamExt};
+use log::{debug, info, warn};
+use rtnetlink::constants::{RTMGRP_IPV4_IFADDR, RTMGRP_LINK};
+use rtnetlink::packet::address::nlas::Nla as AddressNla;
+use rtnetlink::packet::link::nlas::Nla as LinkNla;
+use rtnetlink::packet::{AddressMessage, LinkMessage, RtnlMessage, AF_INET};
+use rtnetlink::proto::packet::NetlinkMessage;
+use rtnetlink::sys::{AsyncSocket, SocketAddr};
+use rtnetlink::Handle;
+use sprayer::config::NetworkSpec;
+use tokio::sync::mpsc::{Sender, UnboundedReceiver};
+
+/// Which interfaces carry workloads, `NAME` for a single interface or
+/// `vrf:NAME` for every member of a VRF.
+#[derive(Clone, Debug)]
+pub enum Selector {
+    Name(String),
+    Vrf(String),
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("vrf", vrf)) if !vrf.is_empty() => Ok(Selector::Vrf(vrf.to_string())),
+            Some(_) => Err(format!("invalid workload {s}, expected NAME or vrf:NAME")),
+            None => Ok(Selector::Name(s.to_string())),
+        }
+    }
+}
+
+/// A workload interface and its overlay addresses, in host byte order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Workload {
+    pub ifidx: u32,
+    pub mac: [u8; 6],
+    pub addrs: Vec<u32>,
+}
+
+impl Workload {
+    /// INTERFACE entries for the workload's addresses, reached through this
+    /// host's underlay address `next_hop`.
+    pub fn endpoints(&self, next_hop: u32) -> impl Iterator<Item = (u32, Interface)> + '_ {
+        self.addrs.iter().map(move |addr| {
+            (*addr, Interface {
+                mac: self.mac,
+                ifidx: self.ifidx,
+                next_hop,
+                flags: INTERFACE_FLAG_LOCAL,
+            })
+        })
+    }
+}
+
+/// Workloads that went away or changed, and their current state.
+#[derive(Debug)]
+pub struct Change {
+    pub removed: Vec<Workload>,
+    pub added: Vec<Workload>,
+}
+
+/// Finds workload interfaces over rtnetlink and follows links and addresses
+/// as they come and go.
+pub struct Discovery {
+    handle: Handle,
+    messages: UnboundedReceiver<(NetlinkMessage<RtnlMessage>, SocketAddr)>,
+    selectors: Vec<Selector>,
+    networks: Vec<NetworkSpec>,
+    current: HashMap<u32, Workload>,
+}
+
+impl Discovery {
+    /// Must be called from within the runtime, which drives the connection.
+    pub fn new(selectors: Vec<Selector>, networks: Vec<NetworkSpec>) -> Result<Self, std::io::Error> {
+        let (mut connection, handle, messages) = rtnetlink::new_connection()?;
+        connection
+            .socket_mut()
+            .socket_mut()
+            .bind(&SocketAddr::new(0, RTMGRP_LINK | RTMGRP_IPV4_IFADDR))?;
+        tokio::spawn(connection);
+        Ok(Discovery {
+            handle,
+            messages,
+            selectors,
+            networks,
+            current: HashMap::new(),
+        })
+    }
+
+    /// The workloads present right now.
+    pub async fn scan(&mut self) -> Result<Vec<Workload>, anyhow::Error> {
+        let links: Vec<LinkMessage> = self.handle.link().get().execute().try_collect().await?;
+        let addresses: Vec<AddressMessage> = self.handle.address().get().execute().try_collect().await?;
+
+        let name = |link: &LinkMessage| {
+            link.nlas.iter().find_map(|nla| match nla {
+                LinkNla::IfName(name) => Some(name.clone()),
+                _ => None,
+            })
+        };
+        let index_of = |wanted: &str| {
+            links.iter().find(|link| name(link).as_deref() == Some(wanted)).map(|link| link.header.index)
+        };
+        let mut selected = Vec::new();
+        for selector in &self.selectors {
+            match selector {
+                Selector::Name(wanted) => selected.extend(index_of(wanted)),
+                Selector::Vrf(vrf) => {
+                    let vrf = match index_of(vrf) {
+                        Some(vrf) => vrf,
+                        None => continue,
+                    };
+                    selected.extend(links.iter()
+                        .filter(|link| link.nlas.contains(&LinkNla::Master(vrf)))
+                        .map(|link| link.header.index));
+                }
+            }
+        }
+
+        let mut workloads = HashMap::new();
+        for link in links.iter().filter(|link| selected.contains(&link.header.index)) {
+            let mac = link.nlas.iter().find_map(|nla| match nla {
+                LinkNla::Address(mac) => <[u8; 6]>::try_from(mac.as_slice()).ok(),
+                _ => None,
+            });
+            let mac = match mac {
+                Some(mac) => mac,
+                None => {
+                    debug!("skipping {:?}, it has no mac address", name(link));
+                    continue;
+                }
+            };
+            let addrs = addresses.iter()
+                .filter(|addr| addr.header.index == link.header.index && addr.header.family == AF_INET as u8)
+                .filter_map(|addr| addr.nlas.iter().find_map(|nla| match nla {
+                    AddressNla::Address(ip) => <[u8; 4]>::try_from(ip.as_slice()).ok(),
+                    _ => None,
+                }))
+                .map(u32::from_be_bytes)
+                .filter(|ip| self.networks.iter().any(|network| network.contains(*ip)))
+                .collect();
+            workloads.insert(link.header.index, Workload { ifidx: link.header.index, mac, addrs });
+        }
+        self.current = workloads;
+        Ok(self.current.values().cloned().collect())
+    }
+
+    /// Rescans whenever a link or address changes and reports the difference.
+    /// A failed rescan is logged and retried on the next change.
+    pub async fn watch(mut self, tx: Sender<Change>) -> Result<(), anyhow::Error> {
+        while self.messages.next().await.is_some() {
+            // Drain the burst a veth pair or an address change produces.
+            while let Ok(Some(_)) = tokio::time::timeout(std::time::Duration::from_millis(50), self.messages.next()).await {}
+            let before = std::mem::take(&mut self.current);
+            let after = match self.scan().await {
+                Ok(after) => after,
+                Err(e) => {
+                    warn!("failed to rescan workloads: {:#}", e);
+                    self.current = before;
+                    continue;
+                }
+            };
+            let removed: Vec<Workload> = before.values()
+                .filter(|workload| self.current.get(&workload.ifidx) != Some(workload))
+                .cloned()
+                .collect();
+            let added: Vec<Workload> = after.into_iter()
+                .filter(|workload| before.get(&workload.ifidx) != Some(workload))
+                .collect();
+            if removed.is_empty() && added.is_empty() {
+                continue;
+            }
+            info!("workloads changed, {} gone, {} new", removed.len(), added.len());
+            if tx.send(Change { removed, added }).await.is_err() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use std::borrow::BorrowMut;
+use std::path::Path;
+
+use anyhow::Context;
+use aya::maps::{DevMapHash, HashMap, Map, MapData, MapError};
+use common::{Interface, INTERFACE_FLAG_LOCAL};
+
+/// The pinned maps describing where overlay endpoints live: INTERFACE, read by
+/// both data path programs, and DEVMAP, holding the ports they redirect to.
+pub struct EndpointMaps<T> {
+    pub interfaces: HashMap<T, u32, Interface>,
+    pub ports: DevMapHash<T>,
+}
+
+impl EndpointMaps<MapData> {
+    pub fn open_pinned(pin_path: &Path) -> Result<Self, anyhow::Error> {
+        let path = pin_path.join("INTERFACE");
+        let interfaces = MapData::from_pin(&path)
+            .with_context(|| format!("failed to open {}, is the sprayer running?", path.display()))?;
+        let path = pin_path.join("DEVMAP");
+        let ports = MapData::from_pin(&path)
+            .with_context(|| format!("failed to open {}, is the sprayer running?", path.display()))?;
+        Ok(EndpointMaps {
+            interfaces: HashMap::try_from(Map::HashMap(interfaces))?,
+            ports: DevMapHash::try_from(Map::DevMapHash(ports))?,
+        })
+    }
+}
+
+impl<T: BorrowMut<MapData>> EndpointMaps<T> {
+    pub fn get(&self, ip: u32) -> Result<Option<Interface>, anyhow::Error> {
+        match self.interfaces.get(&ip, 0) {
+            Ok(intf) => Ok(Some(intf)),
+            Err(MapError::KeyNotFound) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The endpoints on this host, whether the daemon or the CNI plugin
+    /// registered them, in ascending order.
+    pub fn local(&self) -> Vec<u32> {
+        let mut local: Vec<u32> = self.interfaces.iter()
+            .filter_map(Result::ok)
+            .filter(|(_, intf)| intf.flags & INTERFACE_FLAG_LOCAL != 0)
+            .map(|(ip, _)| ip)
+            .collect();
+        local.sort_unstable();
+        local
+    }
+
+    /// The ports of the endpoints on this host, the redirect targets this
+    /// host set up itself, in ascending order.
+    pub fn local_ports(&self) -> Vec<u32> {
+        let mut ports: Vec<u32> = self.interfaces.iter()
+            .filter_map(Result::ok)
+            .filter(|(_, intf)| intf.flags & INTERFACE_FLAG_LOCAL != 0 && intf.ifidx != 0)
+            .map(|(_, intf)| intf.ifidx)
+            .collect();
+        ports.sort_unstable();
+        ports.dedup();
+        ports
+    }
+
+    /// Registers `ip` behind `intf`. Endpoints on this host also get their
+    /// port added as a redirect target.
+    pub fn add(&mut self, ip: u32, intf: Interface) -> Result<(), anyhow::Error> {
+        if intf.ifidx != 0 {
+            self.ports.insert(intf.ifidx, intf.ifidx, None, 0)?;
+        }
+        self.interfaces.insert(ip, intf, 0)?;
+        Ok(())
+    }
+
+    /// Removes `ip` and, once no other endpoint uses it, its port.
+    pub fn remove(&mut self, ip: u32) -> Result<Option<Interface>, anyhow::Error> {
+        let intf = match self.get(ip)? {
+            Some(intf) => intf,
+            None => return Ok(None),
+        };
+        self.interfaces.remove(&ip)?;
+        let shared = self.interfaces.iter()
+            .filter_map(Result::ok)
+            .any(|(_, other)| other.ifidx == intf.ifidx);
+        if intf.ifidx != 0 && !shared {
+            // Already gone if the daemon never programmed it.
+            let _ = self.ports.remove(intf.ifidx);
+        }
+        Ok(Some(intf))
+    }
+
+    /// Removes the endpoints `keep` returns false for, as `remove` does.
+    pub fn retain(&mut self, mut keep: impl FnMut(u32, &Interface) -> bool) -> Result<(), anyhow::Error> {
+        let gone: Vec<u32> = self.interfaces.iter()
+            .filter_map(Result::ok)
+            .filter(|(ip, intf)| !keep(*ip, intf))
+            .map(|(ip, _)| ip)
+            .collect();
+        for ip in gone {
+            self.remove(ip)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the ports no endpoint is behind.
+    pub fn release_ports(&mut self) -> Result<(), anyhow::Error> {
+        let used: Vec<u32> = self.interfaces.iter()
+            .filter_map(Result::ok)
+            .map(|(_, intf)| intf.ifidx)
+            .collect();
+        let unused: Vec<u32> = self.ports.keys()
+            .filter_map(Result::ok)
+            .filter(|ifidx| !used.contains(ifidx))
+            .collect();
+        for ifidx in unused {
+            let _ = self.ports.remove(ifidx);
+        }
+        Ok(())
+    }
+}
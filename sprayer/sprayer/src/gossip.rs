@@ -0,0 +1,755 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use blake2::digest::Mac;
+use blake2::Blake2sMac256;
+use log::{debug, info, warn};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+
+// SWIM style failure detection: one direct probe per protocol period, indirect
+// probes through other members when it goes unanswered, and a suspicion phase
+// the member can refute before it is declared dead. Membership changes are
+// piggybacked on the probe traffic.
+const PROTOCOL_PERIOD: Duration = Duration::from_secs(1);
+const PROBE_TIMEOUT: Duration = Duration::from_millis(400);
+const SUSPECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEAD_RETAIN: Duration = Duration::from_secs(30);
+const INDIRECT_PROBES: usize = 3;
+const RETRANSMIT_MULT: u32 = 3;
+const MAX_PACKET: usize = 1400;
+// Truncated Blake2s MAC closing every message when gossip is authenticated.
+const TAG_LEN: usize = 16;
+
+const MSG_PING: u8 = 1;
+const MSG_ACK: u8 = 2;
+const MSG_PING_REQ: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Alive = 0,
+    Suspect = 1,
+    Dead = 2,
+}
+
+impl State {
+    fn from_u8(v: u8) -> Option<State> {
+        match v {
+            0 => Some(State::Alive),
+            1 => Some(State::Suspect),
+            2 => Some(State::Dead),
+            _ => None,
+        }
+    }
+}
+
+/// What the daemon has to program for a remote host.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The host at `underlay` is alive and owns these overlay addresses. Its
+    /// tunnels may also come from the network sources in `sources`.
+    Endpoints { underlay: Ipv4Addr, endpoints: Vec<u32>, sources: Vec<Ipv4Addr> },
+    /// The host at `underlay` is dead, all of its endpoints are gone.
+    Down { underlay: Ipv4Addr },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Update {
+    addr: Ipv4Addr,
+    incarnation: u32,
+    state: State,
+    endpoints: Vec<u32>,
+    sources: Vec<Ipv4Addr>,
+}
+
+impl Update {
+    fn encoded_len(&self) -> usize {
+        12 + 4 * self.endpoints.len() + 4 * self.sources.len()
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.addr.octets());
+        buf.extend_from_slice(&self.incarnation.to_be_bytes());
+        buf.push(self.state as u8);
+        buf.extend_from_slice(&(self.endpoints.len() as u16).to_be_bytes());
+        for ep in &self.endpoints {
+            buf.extend_from_slice(&ep.to_be_bytes());
+        }
+        buf.push(self.sources.len() as u8);
+        for src in &self.sources {
+            buf.extend_from_slice(&src.octets());
+        }
+    }
+
+    fn decode(buf: &mut &[u8]) -> Option<Update> {
+        let addr = Ipv4Addr::from(read_u32(buf)?);
+        let incarnation = read_u32(buf)?;
+        let state = State::from_u8(read_u8(buf)?)?;
+        let n = read_u16(buf)?;
+        let mut endpoints = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            endpoints.push(read_u32(buf)?);
+        }
+        let n = read_u8(buf)?;
+        let mut sources = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            sources.push(Ipv4Addr::from(read_u32(buf)?));
+        }
+        Some(Update { addr, incarnation, state, endpoints, sources })
+    }
+}
+
+/// The addresses to unprogram and program for the host an event is about.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Assignment {
+    pub underlay: Ipv4Addr,
+    pub stale: Vec<u32>,
+    pub endpoints: Vec<u32>,
+}
+
+/// Which remote host each overlay address was programmed for, so a host only
+/// ever takes back the addresses it still owns.
+#[derive(Default)]
+pub struct Owners {
+    owners: HashMap<u32, Ipv4Addr>,
+}
+
+impl Owners {
+    /// Records the addresses `event` assigns to its host. Addresses for which
+    /// `is_local` holds are workloads of this host and neither taken over nor
+    /// given back.
+    pub fn assign(&mut self, event: Event, is_local: impl Fn(u32) -> bool) -> Assignment {
+        let (underlay, endpoints) = match event {
+            Event::Endpoints { underlay, endpoints, .. } => (underlay, endpoints),
+            Event::Down { underlay } => (underlay, Vec::new()),
+        };
+        let endpoints: Vec<u32> = endpoints.into_iter().filter(|ip| !is_local(*ip)).collect();
+        let mut stale = Vec::new();
+        self.owners.retain(|ip, owner| {
+            if *owner != underlay || endpoints.contains(ip) {
+                return true;
+            }
+            if !is_local(*ip) {
+                stale.push(*ip);
+            }
+            false
+        });
+        for ip in &endpoints {
+            self.owners.insert(*ip, underlay);
+        }
+        Assignment { underlay, stale, endpoints }
+    }
+}
+
+fn read_u8(buf: &mut &[u8]) -> Option<u8> {
+    let (v, rest) = buf.split_first()?;
+    *buf = rest;
+    Some(*v)
+}
+
+fn read_u16(buf: &mut &[u8]) -> Option<u16> {
+    let v = buf.get(..2)?;
+    let v = u16::from_be_bytes([v[0], v[1]]);
+    *buf = &buf[2..];
+    Some(v)
+}
+
+fn read_u32(buf: &mut &[u8]) -> Option<u32> {
+    let v = buf.get(..4)?;
+    let v = u32::from_be_bytes([v[0], v[1], v[2], v[3]]);
+    *buf = &buf[4..];
+    Some(v)
+}
+
+/// The gossip key derived from the tunnel pre-shared key, for when no
+/// separate gossip key is configured.
+pub fn derive_key(psk: &[u8; 32]) -> [u8; 32] {
+    let mut mac = <Blake2sMac256 as Mac>::new_from_slice(psk).expect("blake2s accepts 32 byte keys");
+    mac.update(b"sprayer gossip");
+    mac.finalize().into_bytes().into()
+}
+
+fn mac(key: &[u8; 32], msg: &[u8]) -> Blake2sMac256 {
+    let mut mac = <Blake2sMac256 as Mac>::new_from_slice(key).expect("blake2s accepts 32 byte keys");
+    mac.update(msg);
+    mac
+}
+
+fn random() -> u32 {
+    let mut v = [0u8; 4];
+    unsafe { libc::getrandom(v.as_mut_ptr() as *mut libc::c_void, v.len(), 0) };
+    u32::from_ne_bytes(v)
+}
+
+struct Member {
+    incarnation: u32,
+    state: State,
+    since: Instant,
+    endpoints: Vec<u32>,
+    sources: Vec<Ipv4Addr>,
+}
+
+struct Probe {
+    target: Ipv4Addr,
+    seq: u32,
+    sent: Instant,
+    indirect: bool,
+}
+
+pub struct Gossip {
+    sock: UdpSocket,
+    addr: Ipv4Addr,
+    port: u16,
+    key: Option<[u8; 32]>,
+    incarnation: u32,
+    endpoints: Vec<u32>,
+    sources: Vec<Ipv4Addr>,
+    seeds: Vec<Ipv4Addr>,
+    members: HashMap<Ipv4Addr, Member>,
+    broadcasts: Vec<(Update, u32)>,
+    seq: u32,
+    probe: Option<Probe>,
+    probe_order: Vec<Ipv4Addr>,
+    // Ping requests we forwarded: our sequence number -> requester and the
+    // sequence number it is waiting for.
+    forwards: HashMap<u32, (SocketAddr, u32, Instant)>,
+    events: Sender<Event>,
+}
+
+impl Gossip {
+    /// Messages are authenticated with `key` if given. Without it any host
+    /// that reaches the port can join and advertise endpoints.
+    pub async fn bind(
+        addr: Ipv4Addr,
+        port: u16,
+        key: Option<[u8; 32]>,
+        seeds: Vec<Ipv4Addr>,
+        endpoints: Vec<u32>,
+        sources: Vec<Ipv4Addr>,
+        events: Sender<Event>,
+    ) -> Result<Self, std::io::Error> {
+        let sock = UdpSocket::bind(SocketAddrV4::new(addr, port)).await?;
+        info!("gossip listening on {}:{}", addr, port);
+        Ok(Gossip {
+            sock,
+            addr,
+            port,
+            key,
+            incarnation: 0,
+            endpoints,
+            sources,
+            seeds: seeds.into_iter().filter(|seed| *seed != addr).collect(),
+            members: HashMap::new(),
+            broadcasts: Vec::new(),
+            seq: random(),
+            probe: None,
+            probe_order: Vec::new(),
+            forwards: HashMap::new(),
+            events,
+        })
+    }
+
+    /// Runs the protocol, advertising whatever `local` holds as our
+    /// endpoints from then on.
+    pub async fn run(mut self, mut local: watch::Receiver<Vec<u32>>) -> Result<(), std::io::Error> {
+        let mut buf = [0u8; 65536];
+        let mut interval = tokio::time::interval(PROTOCOL_PERIOD / 4);
+        let mut next_probe = Instant::now();
+        let mut watching = true;
+        loop {
+            tokio::select! {
+                res = self.sock.recv_from(&mut buf) => {
+                    let (len, from) = res?;
+                    self.handle(&buf[..len], from).await;
+                }
+                changed = local.changed(), if watching => {
+                    match changed {
+                        Ok(()) => {
+                            let endpoints = local.borrow_and_update().clone();
+                            self.set_endpoints(endpoints);
+                        }
+                        Err(_) => watching = false,
+                    }
+                }
+                _ = interval.tick() => {
+                    let now = Instant::now();
+                    self.check_probe(now).await;
+                    self.check_suspects(now).await;
+                    if self.probe.is_none() && now >= next_probe {
+                        self.start_probe(now).await;
+                        next_probe = now + PROTOCOL_PERIOD;
+                    }
+                }
+            }
+        }
+    }
+
+    // Others only take a new incarnation from us as news.
+    fn set_endpoints(&mut self, endpoints: Vec<u32>) {
+        if endpoints == self.endpoints {
+            return;
+        }
+        info!("advertising {} local endpoints", endpoints.len());
+        self.endpoints = endpoints;
+        self.incarnation += 1;
+        let update = self.local_update();
+        self.enqueue(update);
+    }
+
+    fn local_update(&self) -> Update {
+        Update {
+            addr: self.addr,
+            incarnation: self.incarnation,
+            state: State::Alive,
+            endpoints: self.endpoints.clone(),
+            sources: self.sources.clone(),
+        }
+    }
+
+    fn enqueue(&mut self, update: Update) {
+        self.broadcasts.retain(|(queued, _)| queued.addr != update.addr);
+        let n = self.members.len() as u32 + 1;
+        let transmits = RETRANSMIT_MULT * (32 - n.leading_zeros());
+        self.broadcasts.push((update, transmits));
+    }
+
+    fn encode(&mut self, msg: u8, seq: u32, target: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut buf = vec![msg];
+        buf.extend_from_slice(&seq.to_be_bytes());
+        if let Some(target) = target {
+            buf.extend_from_slice(&target.octets());
+        }
+        // Our own state always goes first so receivers learn about us from
+        // any message; queued updates follow while they fit.
+        let local = self.local_update();
+        let count_pos = buf.len();
+        buf.push(1);
+        local.encode(&mut buf);
+        let mut count = 1u8;
+        for (update, transmits) in self.broadcasts.iter_mut() {
+            if count == u8::MAX || buf.len() + update.encoded_len() + TAG_LEN > MAX_PACKET {
+                break;
+            }
+            update.encode(&mut buf);
+            *transmits -= 1;
+            count += 1;
+        }
+        buf[count_pos] = count;
+        self.broadcasts.retain(|(_, transmits)| *transmits > 0);
+        if let Some(key) = &self.key {
+            let tag = mac(key, &buf).finalize().into_bytes();
+            buf.extend_from_slice(&tag[..TAG_LEN]);
+        }
+        buf
+    }
+
+    // Strips and checks the MAC, None if the message must be ignored.
+    fn authenticate<'a>(&self, buf: &'a [u8]) -> Option<&'a [u8]> {
+        let Some(key) = &self.key else { return Some(buf) };
+        let (msg, tag) = buf.split_at(buf.len().checked_sub(TAG_LEN)?);
+        mac(key, msg).verify_truncated_left(tag).ok()?;
+        Some(msg)
+    }
+
+    async fn send(&mut self, to: Ipv4Addr, msg: u8, seq: u32, target: Option<Ipv4Addr>) {
+        let buf = self.encode(msg, seq, target);
+        if let Err(e) = self.sock.send_to(&buf, SocketAddrV4::new(to, self.port)).await {
+            debug!("failed to send gossip to {}: {}", to, e);
+        }
+    }
+
+    async fn send_to(&mut self, to: SocketAddr, msg: u8, seq: u32) {
+        let buf = self.encode(msg, seq, None);
+        if let Err(e) = self.sock.send_to(&buf, to).await {
+            debug!("failed to send gossip to {}: {}", to, e);
+        }
+    }
+
+    fn next_seq(&mut self) -> u32 {
+        self.seq = self.seq.wrapping_add(1);
+        self.seq
+    }
+
+    async fn handle(&mut self, buf: &[u8], from: SocketAddr) {
+        let Some(mut buf) = self.authenticate(buf) else {
+            debug!("unauthenticated gossip message from {}", from);
+            return;
+        };
+        let Some(msg) = read_u8(&mut buf) else { return };
+        let Some(seq) = read_u32(&mut buf) else { return };
+        let target = if msg == MSG_PING_REQ {
+            match read_u32(&mut buf) {
+                Some(target) => Some(Ipv4Addr::from(target)),
+                None => return,
+            }
+        } else {
+            None
+        };
+        let Some(count) = read_u8(&mut buf) else { return };
+        for _ in 0..count {
+            match Update::decode(&mut buf) {
+                Some(update) => self.apply(update).await,
+                None => {
+                    warn!("malformed gossip message from {}", from);
+                    return;
+                }
+            }
+        }
+
+        match msg {
+            MSG_PING => self.send_to(from, MSG_ACK, seq).await,
+            MSG_ACK => {
+                if let Some((requester, their_seq, _)) = self.forwards.remove(&seq) {
+                    self.send_to(requester, MSG_ACK, their_seq).await;
+                } else if self.probe.as_ref().map(|p| p.seq) == Some(seq) {
+                    self.probe = None;
+                }
+            }
+            MSG_PING_REQ => {
+                if let Some(target) = target {
+                    let our_seq = self.next_seq();
+                    self.forwards.insert(our_seq, (from, seq, Instant::now()));
+                    self.send(target, MSG_PING, our_seq, None).await;
+                }
+            }
+            _ => debug!("unknown gossip message {} from {}", msg, from),
+        }
+    }
+
+    async fn apply(&mut self, update: Update) {
+        if update.addr == self.addr {
+            // Refute rumours about our own death.
+            if update.state != State::Alive && update.incarnation >= self.incarnation {
+                self.incarnation = update.incarnation + 1;
+                info!("refuting {:?} with incarnation {}", update.state, self.incarnation);
+            }
+            return;
+        }
+        let now = Instant::now();
+        let accept = match self.members.get(&update.addr) {
+            None => update.state != State::Dead,
+            Some(member) => match update.state {
+                State::Alive => update.incarnation > member.incarnation
+                    || (member.state == State::Dead && update.incarnation >= member.incarnation),
+                State::Suspect => update.incarnation > member.incarnation
+                    || (update.incarnation == member.incarnation && member.state == State::Alive),
+                State::Dead => update.incarnation >= member.incarnation && member.state != State::Dead,
+            },
+        };
+        if !accept {
+            return;
+        }
+
+        let known = self.members.contains_key(&update.addr);
+        let was_up = self.members.get(&update.addr).map(|m| m.state != State::Dead).unwrap_or(false);
+        let changed = self.members.get(&update.addr)
+            .map(|m| m.endpoints != update.endpoints || m.sources != update.sources)
+            .unwrap_or(true);
+        self.members.insert(update.addr, Member {
+            incarnation: update.incarnation,
+            state: update.state,
+            since: now,
+            endpoints: update.endpoints.clone(),
+            sources: update.sources.clone(),
+        });
+
+        match update.state {
+            State::Dead => {
+                info!("member {} is dead", update.addr);
+                self.emit(Event::Down { underlay: update.addr }).await;
+            }
+            _ => {
+                if !was_up {
+                    info!("member {} joined with {} endpoints", update.addr, update.endpoints.len());
+                }
+                if !was_up || changed {
+                    self.emit(Event::Endpoints {
+                        underlay: update.addr,
+                        endpoints: update.endpoints.clone(),
+                        sources: update.sources.clone(),
+                    }).await;
+                }
+            }
+        }
+        self.enqueue(update);
+
+        // Bring a newcomer up to date by disseminating everything we know.
+        if !known {
+            let updates: Vec<Update> = self.members.iter()
+                .filter(|(_, m)| m.state != State::Dead)
+                .map(|(addr, m)| Update {
+                    addr: *addr,
+                    incarnation: m.incarnation,
+                    state: m.state,
+                    endpoints: m.endpoints.clone(),
+                    sources: m.sources.clone(),
+                })
+                .collect();
+            for update in updates {
+                if !self.broadcasts.iter().any(|(queued, _)| queued.addr == update.addr) {
+                    self.enqueue(update);
+                }
+            }
+        }
+    }
+
+    async fn emit(&mut self, event: Event) {
+        if self.events.send(event).await.is_err() {
+            warn!("gossip event receiver is gone");
+        }
+    }
+
+    fn live_members(&self) -> Vec<Ipv4Addr> {
+        self.members.iter()
+            .filter(|(_, m)| m.state != State::Dead)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    async fn start_probe(&mut self, now: Instant) {
+        if self.probe_order.is_empty() {
+            self.probe_order = self.live_members();
+            // Keep knocking on the seeds until they answer.
+            for seed in &self.seeds {
+                if !self.members.contains_key(seed) {
+                    self.probe_order.push(*seed);
+                }
+            }
+            // Fisher-Yates, so every member is probed once per round in
+            // random order.
+            for i in (1..self.probe_order.len()).rev() {
+                let j = random() as usize % (i + 1);
+                self.probe_order.swap(i, j);
+            }
+        }
+        let Some(target) = self.probe_order.pop() else { return };
+        let seq = self.next_seq();
+        self.probe = Some(Probe { target, seq, sent: now, indirect: false });
+        self.send(target, MSG_PING, seq, None).await;
+    }
+
+    async fn check_probe(&mut self, now: Instant) {
+        let Some(probe) = self.probe.as_mut() else { return };
+        let elapsed = now - probe.sent;
+        if !probe.indirect && elapsed > PROBE_TIMEOUT {
+            probe.indirect = true;
+            let (target, seq) = (probe.target, probe.seq);
+            let mut helpers: Vec<Ipv4Addr> = self.live_members().into_iter()
+                .filter(|addr| *addr != target)
+                .collect();
+            while helpers.len() > INDIRECT_PROBES {
+                helpers.swap_remove(random() as usize % helpers.len());
+            }
+            for helper in helpers {
+                self.send(helper, MSG_PING_REQ, seq, Some(target)).await;
+            }
+        } else if elapsed > PROTOCOL_PERIOD {
+            let target = probe.target;
+            self.probe = None;
+            if let Some(member) = self.members.get(&target) {
+                if member.state == State::Alive {
+                    info!("suspecting member {}", target);
+                    let update = Update {
+                        addr: target,
+                        incarnation: member.incarnation,
+                        state: State::Suspect,
+                        endpoints: member.endpoints.clone(),
+                        sources: member.sources.clone(),
+                    };
+                    self.apply(update).await;
+                }
+            }
+        }
+    }
+
+    async fn check_suspects(&mut self, now: Instant) {
+        let expired: Vec<Update> = self.members.iter()
+            .filter(|(_, m)| m.state == State::Suspect && now - m.since > SUSPECT_TIMEOUT)
+            .map(|(addr, m)| Update {
+                addr: *addr,
+                incarnation: m.incarnation,
+                state: State::Dead,
+                endpoints: Vec::new(),
+                sources: Vec::new(),
+            })
+            .collect();
+        for update in expired {
+            self.apply(update).await;
+        }
+        self.members.retain(|_, m| m.state != State::Dead || now - m.since < DEAD_RETAIN);
+        self.forwards.retain(|_, (_, _, sent)| now - *sent < PROTOCOL_PERIOD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(state: State, incarnation: u32, endpoints: Vec<u32>) -> Update {
+        Update { addr: Ipv4Addr::new(192, 168, 0, 2), incarnation, state, endpoints, sources: Vec::new() }
+    }
+
+    #[test]
+    fn update_round_trip() {
+        let mut sent = update(State::Suspect, 7, vec![0x0a000002, 0x0a000003]);
+        sent.sources.push(Ipv4Addr::new(192, 168, 1, 2));
+        let mut buf = Vec::new();
+        sent.encode(&mut buf);
+        assert_eq!(buf.len(), sent.encoded_len());
+        let mut rest = buf.as_slice();
+        assert_eq!(Update::decode(&mut rest), Some(sent));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn update_truncated() {
+        let mut buf = Vec::new();
+        update(State::Alive, 1, vec![0x0a000002]).encode(&mut buf);
+        for len in 0..buf.len() {
+            assert_eq!(Update::decode(&mut &buf[..len]), None);
+        }
+        buf[8] = 3;
+        assert_eq!(Update::decode(&mut buf.as_slice()), None);
+    }
+
+    const A: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+    const B: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 3);
+
+    fn endpoints(underlay: Ipv4Addr, endpoints: Vec<u32>) -> Event {
+        Event::Endpoints { underlay, endpoints, sources: Vec::new() }
+    }
+
+    #[test]
+    fn owners_replace_endpoints() {
+        let mut owners = Owners::default();
+        let assigned = owners.assign(endpoints(A, vec![1, 2]), |_| false);
+        assert_eq!(assigned, Assignment { underlay: A, stale: vec![], endpoints: vec![1, 2] });
+        let assigned = owners.assign(endpoints(A, vec![2, 3]), |_| false);
+        assert_eq!(assigned, Assignment { underlay: A, stale: vec![1], endpoints: vec![2, 3] });
+        let mut assigned = owners.assign(Event::Down { underlay: A }, |_| false);
+        assigned.stale.sort();
+        assert_eq!(assigned, Assignment { underlay: A, stale: vec![2, 3], endpoints: vec![] });
+    }
+
+    #[test]
+    fn owners_keep_local_endpoints() {
+        let mut owners = Owners::default();
+        let assigned = owners.assign(endpoints(A, vec![1, 2]), |ip| ip == 1);
+        assert_eq!(assigned.endpoints, vec![2]);
+        let assigned = owners.assign(Event::Down { underlay: A }, |ip| ip == 1);
+        assert_eq!(assigned.stale, vec![2]);
+        // A workload that moved here while its old host still advertised it.
+        owners.assign(endpoints(A, vec![3]), |_| false);
+        let assigned = owners.assign(Event::Down { underlay: A }, |ip| ip == 3);
+        assert!(assigned.stale.is_empty());
+    }
+
+    #[test]
+    fn owners_follow_moved_endpoints() {
+        let mut owners = Owners::default();
+        owners.assign(endpoints(A, vec![1]), |_| false);
+        owners.assign(endpoints(B, vec![1]), |_| false);
+        let assigned = owners.assign(Event::Down { underlay: A }, |_| false);
+        assert!(assigned.stale.is_empty());
+        let assigned = owners.assign(Event::Down { underlay: B }, |_| false);
+        assert_eq!(assigned.stale, vec![1]);
+    }
+
+    async fn gossip() -> (Gossip, tokio::sync::mpsc::Receiver<Event>) {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let gossip = Gossip::bind(Ipv4Addr::LOCALHOST, 0, None, Vec::new(), vec![1], Vec::new(), tx).await.unwrap();
+        (gossip, rx)
+    }
+
+    #[tokio::test]
+    async fn apply_member_lifecycle() {
+        let (mut gossip, mut rx) = gossip().await;
+        gossip.apply(update(State::Alive, 0, vec![2])).await;
+        assert_eq!(rx.try_recv().ok(), Some(endpoints(A, vec![2])));
+
+        // Suspicion alone changes nothing to program.
+        gossip.apply(update(State::Suspect, 0, vec![2])).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(gossip.members[&A].state, State::Suspect);
+
+        // Only a newer incarnation refutes it.
+        gossip.apply(update(State::Alive, 0, vec![2])).await;
+        assert_eq!(gossip.members[&A].state, State::Suspect);
+        gossip.apply(update(State::Alive, 1, vec![2, 3])).await;
+        assert_eq!(gossip.members[&A].state, State::Alive);
+        assert_eq!(rx.try_recv().ok(), Some(endpoints(A, vec![2, 3])));
+
+        // A new network source is news as well.
+        let mut moved = update(State::Alive, 2, vec![2, 3]);
+        moved.sources.push(Ipv4Addr::new(192, 168, 1, 2));
+        gossip.apply(moved.clone()).await;
+        assert_eq!(rx.try_recv().ok(), Some(Event::Endpoints {
+            underlay: A,
+            endpoints: vec![2, 3],
+            sources: moved.sources,
+        }));
+
+        // Stale death rumours are ignored, current ones are not.
+        gossip.apply(update(State::Dead, 1, vec![])).await;
+        assert!(rx.try_recv().is_err());
+        gossip.apply(update(State::Dead, 2, vec![])).await;
+        assert_eq!(rx.try_recv().ok(), Some(Event::Down { underlay: A }));
+
+        // It can rejoin with the same incarnation after a restart.
+        gossip.apply(update(State::Alive, 2, vec![2])).await;
+        assert_eq!(rx.try_recv().ok(), Some(endpoints(A, vec![2])));
+    }
+
+    #[tokio::test]
+    async fn set_endpoints_disseminates() {
+        let (mut gossip, _rx) = gossip().await;
+        gossip.set_endpoints(vec![1]);
+        assert_eq!(gossip.incarnation, 0);
+        assert!(gossip.broadcasts.is_empty());
+        gossip.set_endpoints(vec![1, 4]);
+        assert_eq!(gossip.incarnation, 1);
+        let (queued, _) = &gossip.broadcasts[0];
+        assert_eq!((queued.addr, queued.incarnation), (gossip.addr, 1));
+        assert_eq!(queued.endpoints, vec![1, 4]);
+    }
+
+    #[tokio::test]
+    async fn authenticated_messages() {
+        let (tx, _rx) = tokio::sync::mpsc::channel(16);
+        let mut gossip = Gossip::bind(Ipv4Addr::LOCALHOST, 0, Some([7; 32]), Vec::new(), vec![1], Vec::new(), tx)
+            .await.unwrap();
+        let mut buf = gossip.encode(MSG_PING, 1, None);
+        let len = buf.len() - TAG_LEN;
+        assert_eq!(gossip.authenticate(&buf), Some(&buf[..len]));
+        assert_eq!(gossip.authenticate(&buf[..len]), None);
+        assert_eq!(gossip.authenticate(&buf[..3]), None);
+        buf[len - 1] ^= 1;
+        assert_eq!(gossip.authenticate(&buf), None);
+
+        gossip.key = Some(derive_key(&[7; 32]));
+        let buf = gossip.encode(MSG_PING, 1, None);
+        gossip.key = Some([7; 32]);
+        assert_eq!(gossip.authenticate(&buf), None);
+    }
+
+    #[tokio::test]
+    async fn apply_dead_unknown_member() {
+        let (mut gossip, mut rx) = gossip().await;
+        gossip.apply(update(State::Dead, 3, vec![])).await;
+        assert!(rx.try_recv().is_err());
+        assert!(gossip.members.is_empty());
+    }
+
+    #[tokio::test]
+    async fn apply_refutes_own_suspicion() {
+        let (mut gossip, mut rx) = gossip().await;
+        let mut rumour = update(State::Suspect, 0, vec![]);
+        rumour.addr = gossip.addr;
+        gossip.apply(rumour).await;
+        assert_eq!(gossip.incarnation, 1);
+        assert!(rx.try_recv().is_err());
+        assert!(gossip.members.is_empty());
+    }
+}
@@ -0,0 +1,127 @@
+use std::borrow::BorrowMut;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::Duration;
+
+use aya::maps::{HashMap, MapData, MapError};
+use common::LearnedEndpoint;
+use log::info;
+
+// bpf_ktime_get_ns() counts CLOCK_MONOTONIC.
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Removes endpoints not heard from within `max_age` and returns the ones
+/// still alive together with their age.
+pub fn age_out<T: BorrowMut<MapData>>(
+    learned: &mut HashMap<T, u32, LearnedEndpoint>,
+    max_age: Duration,
+) -> Result<Vec<(u32, LearnedEndpoint, Duration)>, MapError> {
+    let entries = learned.iter().collect::<Result<Vec<_>, _>>()?;
+    let (alive, expired) = split(entries, monotonic_ns(), max_age);
+    for ip in expired {
+        info!("learned endpoint {} aged out", Ipv4Addr::from(ip));
+        // The data path may have evicted it in the meantime.
+        let _ = learned.remove(&ip);
+    }
+    Ok(alive)
+}
+
+// Sorts `entries` into the ones seen within `max_age` of `now`, with their
+// age, and the addresses of the others.
+fn split(
+    entries: impl IntoIterator<Item = (u32, LearnedEndpoint)>,
+    now: u64,
+    max_age: Duration,
+) -> (Vec<(u32, LearnedEndpoint, Duration)>, Vec<u32>) {
+    let mut alive = Vec::new();
+    let mut expired = Vec::new();
+    for (ip, endpoint) in entries {
+        let age = Duration::from_nanos(now.saturating_sub(endpoint.last_seen));
+        if age > max_age {
+            expired.push(ip);
+        } else {
+            alive.push((ip, endpoint, age));
+        }
+    }
+    (alive, expired)
+}
+
+/// Writes one `overlay-ip underlay-ip age-in-seconds` line per endpoint.
+pub fn export(path: &Path, endpoints: &[(u32, LearnedEndpoint, Duration)]) -> Result<(), std::io::Error> {
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    for (ip, endpoint, age) in endpoints {
+        writeln!(
+            file,
+            "{} {} {}",
+            Ipv4Addr::from(*ip),
+            Ipv4Addr::from(endpoint.underlay_ip),
+            age.as_secs()
+        )?;
+    }
+    std::fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: u64 = 1_000_000_000;
+    const MAX_AGE: Duration = Duration::from_secs(60);
+
+    fn endpoint(underlay_ip: u32, last_seen: u64) -> LearnedEndpoint {
+        LearnedEndpoint { underlay_ip, last_seen }
+    }
+
+    #[test]
+    fn split_ages_out() {
+        let now = 1000 * SEC;
+        let entries = [(1, endpoint(11, now - 10 * SEC)), (2, endpoint(12, now - 61 * SEC))];
+        let (alive, expired) = split(entries, now, MAX_AGE);
+        assert_eq!(alive.len(), 1);
+        assert_eq!((alive[0].0, alive[0].2), (1, Duration::from_secs(10)));
+        assert_eq!(expired, vec![2]);
+        // Exactly at the limit is still alive.
+        let (alive, expired) = split([(3, endpoint(13, now - 60 * SEC))], now, MAX_AGE);
+        assert_eq!((alive.len(), expired.len()), (1, 0));
+    }
+
+    #[test]
+    fn split_keeps_refreshed() {
+        let seen = 1000 * SEC;
+        let later = seen + 90 * SEC;
+        let (_, expired) = split([(1, endpoint(11, seen))], later, MAX_AGE);
+        assert_eq!(expired, vec![1]);
+        // The data path bumps last_seen on every packet from the endpoint.
+        let (alive, expired) = split([(1, endpoint(11, later - SEC))], later, MAX_AGE);
+        assert!(expired.is_empty());
+        assert_eq!(alive[0].2, Duration::from_secs(1));
+        // A clock read before the entry was refreshed counts as age zero.
+        let (alive, _) = split([(1, endpoint(11, later + SEC))], later, MAX_AGE);
+        assert_eq!(alive[0].2, Duration::ZERO);
+    }
+
+    #[test]
+    fn export_writes_alive() {
+        let now = 1000 * SEC;
+        let entries = [
+            (u32::from(Ipv4Addr::new(10, 0, 0, 2)), endpoint(u32::from(Ipv4Addr::new(192, 168, 0, 2)), now - 5 * SEC)),
+            (u32::from(Ipv4Addr::new(10, 0, 0, 3)), endpoint(u32::from(Ipv4Addr::new(192, 168, 0, 3)), now - 70 * SEC)),
+        ];
+        let (alive, _) = split(entries, now, MAX_AGE);
+        let dir = std::env::temp_dir().join(format!("sprayer-learning-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("learned");
+        export(&path, &alive).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        let tmp_left = path.with_extension("tmp").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(written, "10.0.0.2 192.168.0.2 5\n");
+        assert!(!tmp_left);
+    }
+}
@@ -0,0 +1,4 @@
+pub mod config;
+pub mod endpoints;
+pub mod netif;
+pub mod netns;
@@ -1,17 +1,38 @@
+mod arp;
+mod attach;
+mod congestion;
+mod discovery;
+mod gossip;
+mod learning;
+mod neigh;
+mod packet;
+mod peers;
+mod tunnel;
+mod xsk;
+
 use anyhow::Context;
-use aya::maps::HashMap;
-use aya::programs::{tc, SchedClassifier, TcAttachType, Xdp, XdpFlags, ProgramFd, self};
-use aya::{include_bytes_aligned, Bpf, maps::Array};
+use aya::maps::{AsyncPerfEventArray, HashMap, LpmTrie, Map, MapData, PerCpuArray, XskMap};
+use aya::programs::{tc, SchedClassifier, TcAttachType, Xdp, ProgramFd, self};
+use aya::{include_bytes_aligned, Bpf, BpfLoader, maps::Array, util::online_cpus};
 use aya_log::BpfLogger;
 use clap::Parser;
 use log::{info, warn, debug};
 use tokio::signal;
-use common::{NetworkKey,Interface};
-use std::ffi::CString;
+use common::{
+    Network, Interface, LearnedEndpoint, FlowKey, FlowNextHop, PathKey, PathStats, PathWeights,
+    SprayExceptionKey, TunnelPorts, INTERFACE_FLAG_LOCAL, FLOW_FLAG_ELEPHANT, MAX_FIB_RESULTS, FIB_RESULT_ERROR, FIB_RESULT_FRAGMENT,
+    PEER_CHECK_SOURCE, PEER_CHECK_INNER, SPOOF_UNKNOWN_PEER, SPOOF_INNER_SOURCE, MAX_XSK_QUEUES,
+};
+use sprayer::config::{parse_mac, NetworkSpec, RemoteEndpoint};
 use std::net::Ipv4Addr;
 use std::os::raw::c_int;
-use std::io::{Error, ErrorKind};
-use nix::ifaddrs::{getifaddrs, InterfaceAddress};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use xsk::XskSocket;
+use attach::{Attachments, XdpMode};
+use sprayer::endpoints::EndpointMaps;
+use sprayer::netif::{get_interface_index, get_interface_ip_address, get_mac_addresses_and_interface_indexes, get_rx_queue_count, interface_exists, matches_any};
 
 
 #[derive(clap::ValueEnum, Clone, Debug)]
@@ -19,44 +40,114 @@ enum Mode{
     Encap,
     Decap,
     Dummy,
+    /// Encap on the workload interfaces and decap on the physical one
+    Node,
+    /// List the flows currently sprayed as elephants and exit
+    Elephants,
+    /// Print the data path counters and exit
+    Stats,
 }
 
 #[derive(Debug, Parser)]
 struct Opt {
-    #[clap(short, long, default_value = "eth0")]
-    iface: String,
+    /// Interface to attach to, may be repeated and use wildcards, e.g. veth*.
+    /// In node mode these are the workload interfaces and must not match --phy.
+    #[clap(short, long = "iface", default_value = "eth0")]
+    ifaces: Vec<String>,
     #[clap(short, long, default_value = "eth0")]
     phy: String,
+    /// Number of spray paths, each an outer UDP source port
     #[clap(short, long, default_value = "1")]
     links: u16,
     #[clap(value_enum)]
     mode: Mode,
+    /// How XDP programs are attached, auto falls back to generic per interface
+    #[clap(long, value_enum, default_value = "native")]
+    xdp_mode: XdpMode,
+    /// Overlay network, e.g. 10.0.0.0/24,gw=10.0.0.1[,encrypt][,dscp=N][,spray=POLICY][,except=PROTO[:PORT]=POLICY][,flowlet-gap=USEC][,table=N][,direct][,src=IP][,ttl=N][,df=POLICY][,id=counter]
+    #[clap(long = "network", default_value = "10.0.0.0/24,gw=10.0.0.1")]
+    networks: Vec<NetworkSpec>,
+    /// Inner bytes after which a flow is sprayed instead of staying on one path
+    #[clap(long)]
+    elephant_bytes: Option<u64>,
+    /// File holding the hex encoded 32 byte key for encrypted networks
+    #[clap(long)]
+    psk: Option<PathBuf>,
+    /// MAC address answering ARP for overlay endpoints and gateways
+    #[clap(long, default_value = "de:ad:be:ef:ba:be", value_parser = parse_mac)]
+    proxy_mac: [u8; 6],
+    /// Learn remote endpoints from received tunnel traffic (decap)
+    #[clap(long)]
+    learn: bool,
+    /// Seconds after which an idle learned endpoint is removed
+    #[clap(long, default_value = "300")]
+    learn_age: u64,
+    /// File the learned endpoints are exported to
+    #[clap(long)]
+    learned_file: Option<PathBuf>,
+    /// Discover remote endpoints by gossiping with other sprayers (encap)
+    #[clap(long)]
+    gossip: bool,
+    /// UDP port used for gossip on the physical interface address
+    #[clap(long, default_value = "7946")]
+    gossip_port: u16,
+    /// File holding the hex encoded 32 byte key authenticating gossip, derived from --psk if not given
+    #[clap(long)]
+    gossip_key: Option<PathBuf>,
+    /// Underlay address of a sprayer to join through
+    #[clap(long = "gossip-seed")]
+    gossip_seeds: Vec<Ipv4Addr>,
+    /// Exchange per path congestion feedback with other sprayers
+    #[clap(long)]
+    feedback: bool,
+    /// UDP port congestion reports are sent to
+    #[clap(long, default_value = "7947")]
+    feedback_port: u16,
+    /// Overlay address behind a remote sprayer, e.g. 10.0.0.2@192.168.0.2, unless gossip finds it (encap)
+    #[clap(long = "remote")]
+    remotes: Vec<RemoteEndpoint>,
+    /// Workload interface to register, by name or as vrf:NAME for all members of a VRF
+    #[clap(long = "workload")]
+    workloads: Vec<discovery::Selector>,
+    /// Destination port of plain tunnels, the same on every sprayer
+    #[clap(long, default_value = "3000")]
+    tunnel_port: u16,
+    /// Spread plain tunnels over this many ports from --tunnel-port by path
+    #[clap(long, default_value = "1")]
+    tunnel_port_count: u16,
+    /// Destination port of encrypted tunnels
+    #[clap(long, default_value = "3001")]
+    crypt_port: u16,
+    /// Underlay address of a sprayer tunnels are accepted from, implies --peer-check (decap)
+    #[clap(long = "peer")]
+    peers: Vec<Ipv4Addr>,
+    /// Drop tunnel traffic from underlay addresses other than --peer and gossip members (node with --gossip)
+    #[clap(long)]
+    peer_check: bool,
+    /// Also drop tunnel traffic whose inner source is not a gossiped endpoint of the sender (node with --gossip)
+    #[clap(long)]
+    check_inner_source: bool,
+    /// Device whose egress encapsulates host-originated overlay traffic (encap)
+    #[clap(long)]
+    tc_egress: Option<String>,
+    /// bpffs directory for maps shared between the encap and decap programs
+    #[clap(long, default_value = "/sys/fs/bpf/sprayer")]
+    pin_path: PathBuf,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let opt = Opt::parse();
 
-    let next_hop_map = std::collections::HashMap::from([
-        ("10.0.0.2".to_string(), "192.168.0.2".to_string()),
-        ("10.0.0.1".to_string(), "192.168.0.1".to_string())
-    ]);
-
-    let interface_map = std::collections::HashMap::from([
-        (ip_to_dec("10.0.0.1"), Interface{
-            mac: mac_to_vec("d2:0f:de:ef:21:30"),
-            ifidx: 67,
-            next_hop: ip_to_dec("192.168.0.1"),
-        }),
-        (ip_to_dec("10.0.0.2"), Interface{
-            mac: mac_to_vec("8a:e5:ee:91:e8:16"),
-            ifidx: 71,
-            next_hop: ip_to_dec("192.168.0.2"),
-        }),
-    ]);
-
     env_logger::init();
 
+    if let Mode::Elephants = opt.mode {
+        return list_elephants(&opt.pin_path);
+    }
+    if let Mode::Stats = opt.mode {
+        return print_stats(&opt.pin_path);
+    }
+
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
     let rlim = libc::rlimit {
@@ -74,23 +165,26 @@ async fn main() -> Result<(), anyhow::Error> {
     // reach for `Bpf::load_file` instead.
     info!("starting...");
 
+    std::fs::create_dir_all(&opt.pin_path)
+        .with_context(|| format!("failed to create {}, is bpffs mounted?", opt.pin_path.display()))?;
+
     #[cfg(debug_assertions)]
-    let mut xdp_encap_bpf = Bpf::load(include_bytes_aligned!(
+    let mut xdp_encap_bpf = BpfLoader::new().map_pin_path(&opt.pin_path).load(include_bytes_aligned!(
         "../../target/bpfel-unknown-none/debug/xdp-encap"
     ))?;
     #[cfg(not(debug_assertions))]
     info!("load release");
-    let mut xdp_encap_bpf = Bpf::load(include_bytes_aligned!(
+    let mut xdp_encap_bpf = BpfLoader::new().map_pin_path(&opt.pin_path).load(include_bytes_aligned!(
         "../../target/bpfel-unknown-none/release/xdp-encap"
     ))?;
 
     #[cfg(debug_assertions)]
-    let mut xdp_decap_bpf = Bpf::load(include_bytes_aligned!(
+    let mut xdp_decap_bpf = BpfLoader::new().map_pin_path(&opt.pin_path).load(include_bytes_aligned!(
         "../../target/bpfel-unknown-none/debug/xdp-decap"
     ))?;
     #[cfg(not(debug_assertions))]
     info!("load release");
-    let mut xdp_decap_bpf = Bpf::load(include_bytes_aligned!(
+    let mut xdp_decap_bpf = BpfLoader::new().map_pin_path(&opt.pin_path).load(include_bytes_aligned!(
         "../../target/bpfel-unknown-none/release/xdp-decap"
     ))?;
 
@@ -115,138 +209,412 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let intf_list = get_mac_addresses_and_interface_indexes();
 
-    let mac_address = "de:ad:be:ef:ba:be";
-    let bytes: Vec<u8> = mac_address
-        .split(':')
-        .map(|s| u8::from_str_radix(s, 16).unwrap())
+    let proxy_mac_addr = opt.proxy_mac;
+    let mut announcer: Option<arp::Announcer> = None;
+    let mut gossip_rx: Option<tokio::sync::mpsc::Receiver<gossip::Event>> = None;
+    let mut gossip_owners = gossip::Owners::default();
+    // The local endpoints gossip advertises.
+    let mut gossip_local: Option<tokio::sync::watch::Sender<Vec<u32>>> = None;
+    let mut feedback_rx: Option<tokio::sync::mpsc::Receiver<congestion::Feedback>> = None;
+    let mut path_weights = congestion::Weights::default();
+    let mut reporter: Option<congestion::Reporter> = None;
+    let mut attachments = Attachments::default();
+    let mut peers = peers::Peers::default();
+    let mut discovery_rx: Option<tokio::sync::mpsc::Receiver<discovery::Change>> = None;
+    let mut local_ips: Vec<u32> = Vec::new();
+
+    // Local workloads are found over rtnetlink and followed while we run.
+    let local_next_hop = get_interface_ip_address(&opt.phy).unwrap_or_default();
+    let mut discovery = discovery::Discovery::new(opt.workloads.clone(), opt.networks.clone())
+        .context("failed to open rtnetlink socket")?;
+    let mut interface_map: std::collections::HashMap<u32, Interface> = discovery.scan().await?
+        .iter()
+        .flat_map(|workload| workload.endpoints(local_next_hop))
         .collect();
-    let mut proxy_mac_addr: [u8; 6] = [0; 6];
-    proxy_mac_addr.copy_from_slice(&bytes[..]);
-    proxy_mac_addr.reverse();
-
-    match opt.mode{
-        Mode::Encap => {
-            info!("encap mode");
-            let ifidx = get_interface_index(&opt.phy)?;
-            info!("phy ifidx {}", ifidx);
-            let phy_intf_addr = if let Some(addr) = get_interface_ip_address(&opt.phy){
-                    addr
-            } else {
-                warn!("failed to find ip");
-                return Ok(())
-            };
-            info!("phy intf addr {}", phy_intf_addr);
-            
-            
-            if let Err(e) = BpfLogger::init(&mut xdp_encap_bpf) {
-                // This can happen if you remove all log statements from your eBPF program.
-                warn!("failed to initialize eBPF logger: {}", e);
+    if !opt.workloads.is_empty() && !matches!(opt.mode, Mode::Dummy) {
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        tokio::spawn(async move {
+            if let Err(e) = discovery.watch(tx).await{
+                warn!("workload discovery failed: {:#}", e);
             }
-            let xdp_program: &mut Xdp = xdp_encap_bpf.program_mut("xdp_encap").unwrap().try_into()?;
-            xdp_program.load()?;
+        });
+        discovery_rx = Some(rx);
+    }
+    let gateways: Vec<u32> = opt.networks.iter()
+        .map(|network| u32::from_be_bytes(network.gateway.octets()))
+        .collect();
+    let tunnel_ports = TunnelPorts {
+        base: opt.tunnel_port as u32,
+        count: opt.tunnel_port_count.max(1) as u32,
+        crypt: opt.crypt_port as u32,
+    };
+    if opt.tunnel_port as u32 + tunnel_ports.count > u16::MAX as u32 + 1 {
+        anyhow::bail!("tunnel port range {}+{} exceeds the port space", opt.tunnel_port, tunnel_ports.count);
+    }
+    if tunnel_ports.is_plain(opt.crypt_port) {
+        anyhow::bail!("crypt port {} is inside the tunnel port range", opt.crypt_port);
+    }
+    // Gossip fills the allow-list and the remote endpoints, and it only runs
+    // alongside the decap program in node mode.
+    let gossiping_node = matches!(opt.mode, Mode::Node) && opt.gossip;
+    if opt.peer_check && opt.peers.is_empty() && !gossiping_node {
+        anyhow::bail!("--peer-check learns the peers from gossip in node mode, list them with --peer instead");
+    }
+    if opt.check_inner_source && !gossiping_node {
+        anyhow::bail!("--check-inner-source needs the endpoints gossip provides in node mode");
+    }
+    let gossip_key = match (&opt.gossip_key, &opt.psk) {
+        (Some(path), _) => Some(tunnel::load_psk(path)?),
+        (None, Some(path)) => Some(gossip::derive_key(&tunnel::load_psk(path)?)),
+        (None, None) => None,
+    };
+    // Anyone could otherwise gossip their way onto the allow-list.
+    if gossiping_node && gossip_key.is_none() && (opt.peer_check || !opt.peers.is_empty() || opt.check_inner_source) {
+        anyhow::bail!("checking peers learnt from gossip needs authenticated gossip, give --gossip-key or --psk");
+    }
+    if opt.gossip && gossip_key.is_none() {
+        warn!("gossip is not authenticated, any host reaching port {} can advertise endpoints", opt.gossip_port);
+    }
+    // A node decapsulates what arrives on the physical interface, the
+    // workload interfaces belong to xdp_encap.
+    let decap_ifaces = match opt.mode {
+        Mode::Node => vec![opt.phy.clone()],
+        _ => opt.ifaces.clone(),
+    };
+    // Both programs would go onto the physical interface, and the default
+    // --iface is the default --phy.
+    if matches!(opt.mode, Mode::Node) && matches_any(&opt.ifaces, &opt.phy) {
+        anyhow::bail!("--iface must not match --phy {} in node mode, give the workload interfaces, e.g. --iface 'veth*'", opt.phy);
+    }
+    // The AF_XDP sockets are registered under their rx queue in one XSKMAP,
+    // so only a single interface can hand frames to the crypto workers.
+    let encrypting = opt.networks.iter().any(|network| network.encrypt);
+    if encrypting && matches!(opt.mode, Mode::Encap | Mode::Node) && !single_interface(&opt.ifaces) {
+        anyhow::bail!("encrypted networks need a single --iface without wildcards");
+    }
+    if opt.psk.is_some() && matches!(opt.mode, Mode::Decap | Mode::Node) && !single_interface(&decap_ifaces) {
+        anyhow::bail!("--psk needs a single --iface without wildcards");
+    }
 
+    if !matches!(opt.mode, Mode::Dummy) {
+        // Left by an earlier run that did not exit cleanly: its remote
+        // endpoints and ports that are gone since.
+        let mut endpoints = EndpointMaps::open_pinned(&opt.pin_path)?;
+        endpoints.retain(|_, intf| intf.flags & INTERFACE_FLAG_LOCAL != 0 && interface_exists(intf.ifidx))?;
+    }
 
-            xdp_program.attach(&opt.iface, XdpFlags::DRV_MODE)
-                .context("failed to attach the XDP program with default flags - try changing XdpFlags::default() to XdpFlags::DRV_MODE")?;
-            
-            if let Some(phy_intf_map) = xdp_encap_bpf.map_mut("PHYINTF"){
-                let mut phy_intf_map: HashMap<_, u8, u32> = HashMap::try_from(phy_intf_map)?;
-                phy_intf_map.insert(&0, &ifidx, 0)?;
-            } else {
-                warn!("LINKS map not found");
+    if let Mode::Encap | Mode::Node = opt.mode {
+        info!("encap mode");
+        let ifidx = get_interface_index(&opt.phy)?;
+        info!("phy ifidx {}", ifidx);
+        let phy_intf_addr = if let Some(addr) = get_interface_ip_address(&opt.phy){
+                addr
+        } else {
+            warn!("failed to find ip");
+            return Ok(())
+        };
+        info!("phy intf addr {}", phy_intf_addr);
+        
+        
+        if let Err(e) = BpfLogger::init(&mut xdp_encap_bpf) {
+            // This can happen if you remove all log statements from your eBPF program.
+            warn!("failed to initialize eBPF logger: {}", e);
+        }
+        let xdp_program: &mut Xdp = xdp_encap_bpf.program_mut("xdp_encap").unwrap().try_into()?;
+        xdp_program.load()?;
+
+
+        attachments.sync("xdp_encap", xdp_program, &opt.ifaces, opt.xdp_mode)?;
+
+        if let Some(dev) = &opt.tc_egress {
+            // The qdisc outlives us, so it may be left from an earlier run.
+            if let Err(e) = tc::qdisc_add_clsact(dev) {
+                if e.raw_os_error() != Some(libc::EEXIST) {
+                    return Err(e).with_context(|| format!("failed to add the clsact qdisc to {}", dev));
+                }
             }
-            if let Some(dev_map) = xdp_encap_bpf.map_mut("DEVMAP"){
-                let mut dev_map: HashMap<_, [u8;6], u32> = HashMap::try_from(dev_map)?;
-                for (k,v) in intf_list{
-                    dev_map.insert(&k, &v, 0)?;
-                }  
-            } else {
-                warn!("DEVMAP map not found");
+            let tc_program: &mut SchedClassifier = xdp_encap_bpf.program_mut("tc_encap").unwrap().try_into()?;
+            tc_program.load()?;
+            tc_program.attach(dev, TcAttachType::Egress)
+                .with_context(|| format!("failed to attach the TC program to {} egress", dev))?;
+            info!("encapsulating host traffic routed to {}", dev);
+        }
+        
+        if let Some(phy_intf_map) = xdp_encap_bpf.map_mut("PHYINTF"){
+            let mut phy_intf_map: HashMap<_, u8, u32> = HashMap::try_from(phy_intf_map)?;
+            phy_intf_map.insert(&0, &ifidx, 0)?;
+        } else {
+            warn!("PHYINTF map not found");
+        }
+        if let Some(links) = xdp_encap_bpf.map_mut("LINKS"){
+            let mut links: HashMap<_, u8, u32> = HashMap::try_from(links)?;
+            links.insert(&0, &(opt.links as u32), 0)?;
+        } else {
+            warn!("LINKS map not found");
+        }
+        if let Some(mac_table) = xdp_encap_bpf.map_mut("MACTABLE"){
+            let mut mac_table: HashMap<_, [u8;6], u32> = HashMap::try_from(mac_table)?;
+            for (k,v) in &intf_list{
+                mac_table.insert(k, v, 0)?;
+            }  
+        } else {
+            warn!("MACTABLE map not found");
+        }
+        // INTERFACE and DEVMAP are pinned so the CNI plugin can register
+        // workloads while we run.
+        let mut endpoints = EndpointMaps::open_pinned(&opt.pin_path)?;
+        endpoints.ports.insert(ifidx, ifidx, None, 0)?;
+        for (_, mac_ifidx) in &intf_list{
+            endpoints.ports.insert(*mac_ifidx, *mac_ifidx, None, 0)?;
+        }
+
+        if let Some(proxy_mac) = xdp_encap_bpf.map_mut("PROXYMAC"){
+            let mut proxy_mac: HashMap<_, u8, [u8;6]> = HashMap::try_from(proxy_mac)?;
+            proxy_mac.insert(&0, &proxy_mac_addr, 0)?;
+        } else {
+            warn!("PROXYMAC map not found");
+        }
+         
+        if let Some(nw_map) = xdp_encap_bpf.map_mut("NETWORKS"){
+            let mut nw_map: LpmTrie<_, u32, Network> = LpmTrie::try_from(nw_map)?;
+            for network in &opt.networks{
+                nw_map.insert(&network.key(), network.value(), 0)?;
             }
+        } else {
+            warn!("NETWORKS map not found");
+        }
 
-            if let Some(proxy_mac) = xdp_encap_bpf.map_mut("PROXYMAC"){
-                let mut proxy_mac: HashMap<_, u8, [u8;6]> = HashMap::try_from(proxy_mac)?;
-                proxy_mac.insert(&0, &proxy_mac_addr, 0)?;
+        if let Some(threshold) = opt.elephant_bytes{
+            if let Some(elephant) = xdp_encap_bpf.map_mut("ELEPHANT"){
+                let mut elephant: HashMap<_, u8, u64> = HashMap::try_from(elephant)?;
+                elephant.insert(&0, &threshold, 0)?;
             } else {
-                warn!("DEVMAP map not found");
+                warn!("ELEPHANT map not found");
             }
-             
-            if let Some(nw_map) = xdp_encap_bpf.map_mut("NETWORKS"){
-                let mut nw_map: HashMap<_, NetworkKey, u32> = HashMap::try_from(nw_map)?;
-                let prefix: Ipv4Addr = "10.0.0.0".parse()?;
-                let prefix_int = u32::from_be_bytes(prefix.octets());
-                let key = NetworkKey{
-                    prefix: prefix_int,
-                    prefix_len: 24,
-                };
-                let gateway: Ipv4Addr = "10.0.0.1".parse()?;
-                let gateway_int = u32::from_be_bytes(gateway.octets());
+        }
+        if let Some(exceptions) = xdp_encap_bpf.map_mut("SPRAYEXCEPT"){
+            let mut exceptions: HashMap<_, SprayExceptionKey, u32> = HashMap::try_from(exceptions)?;
+            for (key, policy) in opt.networks.iter().flat_map(NetworkSpec::exception_entries){
+                exceptions.insert(key, policy, 0)?;
+            }
+        } else {
+            warn!("SPRAYEXCEPT map not found");
+        }
 
-                nw_map.insert(&key, &gateway_int, 0)?;
+        for (dst, intf) in &interface_map{
+            endpoints.add(*dst, *intf)?;
+        }
+        // Remote endpoints are configured here or come from gossip.
+        if opt.remotes.is_empty() && !opt.gossip {
+            warn!("no --remote endpoints and no --gossip, only local workloads are reachable");
+        }
+        for remote in &opt.remotes {
+            let next_hop = u32::from(remote.underlay);
+            endpoints.add(u32::from(remote.ip), Interface{
+                mac: [0; 6],
+                ifidx: 0,
+                next_hop,
+                flags: 0,
+            })?;
+            if let Some(nh_map) = xdp_encap_bpf.map_mut("NEXTHOP"){
+                let mut nh_map: HashMap<_, u32, u32> = HashMap::try_from(nh_map)?;
+                nh_map.insert(u32::from(remote.ip), next_hop, 0)?;
             } else {
-                warn!("NETWORKS map not found");
+                warn!("NEXTHOP map not found");
             }
+        }
 
-            for (dst, nh) in &next_hop_map{
-                let dst_addr: Ipv4Addr = dst.parse()?;
-                let dst_int = u32::from_be_bytes(dst_addr.octets());
-                let nh_addr: Ipv4Addr = nh.parse()?;
-                let nh_int = u32::from_be_bytes(nh_addr.octets());
-                if let Some(nh_map) = xdp_encap_bpf.map_mut("NEXTHOP"){
-                    let mut nh_map: HashMap<_, u32, u32> = HashMap::try_from(nh_map)?;
-                    nh_map.insert(&dst_int, &nh_int, 0)?;
-                } else {
-                    warn!("NEXTHOP map not found");
-                }
+        if let Some(phy_ip) = xdp_encap_bpf.map_mut("PHYIP"){
+            let mut phy_ip: HashMap<_, u8, u32> = HashMap::try_from(phy_ip)?;
+            phy_ip.insert(&0, &phy_intf_addr, 0)?;
+        } else {
+            warn!("PHYIP map not found");
+        }
+        if let Some(ports) = xdp_encap_bpf.map_mut("TUNNELPORTS"){
+            let mut ports: HashMap<_, u8, TunnelPorts> = HashMap::try_from(ports)?;
+            ports.insert(&0, &tunnel_ports, 0)?;
+        } else {
+            warn!("TUNNELPORTS map not found");
+        }
+
+        if let Some(neigh_req) = xdp_encap_bpf.take_map("NEIGHREQ"){
+            let mut neigh_req = AsyncPerfEventArray::try_from(neigh_req)?;
+            for cpu in online_cpus()? {
+                let buf = neigh_req.open(cpu, None)?;
+                tokio::spawn(async move {
+                    if let Err(e) = neigh::resolve(buf).await{
+                        warn!("neighbour resolution failed: {}", e);
+                    }
+                });
             }
+        } else {
+            warn!("NEIGHREQ map not found");
+        }
 
-            for (dst, intf) in &interface_map{;
-                if let Some(intf_map) = xdp_encap_bpf.map_mut("INTERFACE"){
-                    let mut intf_map: HashMap<_, u32, Interface> = HashMap::try_from(intf_map)?;
-                    intf_map.insert(dst, intf, 0)?;
-                } else {
-                    warn!("INTERFACE map not found");
-                }
+        announcer = Some(arp::Announcer::new(proxy_mac_addr, arp_targets(&gateways, &interface_map))?);
+
+        if opt.networks.iter().any(|network| network.encrypt){
+            let psk_path = opt.psk.as_ref().context("encrypted networks require --psk")?;
+            let psk = tunnel::load_psk(psk_path)?;
+            // The only interface, checked above.
+            let xsk_iface = attachments.interfaces("xdp_encap").next().context("no interface to bind the AF_XDP socket to")?;
+            for xsk in bind_xsks(&mut xdp_encap_bpf, xsk_iface)? {
+                std::thread::spawn(move || {
+                    if let Err(e) = tunnel::run_encrypt(xsk, ifidx, psk){
+                        warn!("encryption worker failed: {}", e);
+                    }
+                });
             }
+        }
 
-            if let Some(phy_ip) = xdp_encap_bpf.map_mut("PHYIP"){
-                let mut phy_ip: HashMap<_, u8, u32> = HashMap::try_from(phy_ip)?;
-                phy_ip.insert(&0, &phy_intf_addr, 0)?;
-            } else {
-                warn!("DEVMAP map not found");
+        if opt.gossip{
+            // Includes workloads the CNI plugin registered before we started.
+            let local = endpoints.local();
+            let (local_tx, local_rx) = tokio::sync::watch::channel(local.clone());
+            let (tx, rx) = tokio::sync::mpsc::channel(64);
+            let gossip = gossip::Gossip::bind(
+                Ipv4Addr::from(phy_intf_addr),
+                opt.gossip_port,
+                gossip_key,
+                opt.gossip_seeds.clone(),
+                local,
+                opt.networks.iter().filter_map(|network| network.src).collect(),
+                tx,
+            ).await.context("failed to bind gossip socket")?;
+            tokio::spawn(async move {
+                if let Err(e) = gossip.run(local_rx).await{
+                    warn!("gossip failed: {}", e);
+                }
+            });
+            gossip_rx = Some(rx);
+            gossip_local = Some(local_tx);
+        }
+
+        if opt.feedback{
+            let (tx, rx) = tokio::sync::mpsc::channel(64);
+            let addr = Ipv4Addr::from(phy_intf_addr);
+            let port = opt.feedback_port;
+            tokio::spawn(async move {
+                if let Err(e) = congestion::listen(addr, port, tx).await{
+                    warn!("congestion feedback listener failed: {}", e);
+                }
+            });
+            feedback_rx = Some(rx);
+        }
+    }
+    if let Mode::Dummy = opt.mode {
+        info!("dummy mode");
+        if let Err(e) = BpfLogger::init(&mut xdp_dummy_bpf) {
+            // This can happen if you remove all log statements from your eBPF program.
+            warn!("failed to initialize eBPF logger: {}", e);
+        }
+        let xdp_program: &mut Xdp = xdp_dummy_bpf.program_mut("xdp_dummy").unwrap().try_into()?;
+        xdp_program.load()?;
+        attachments.sync("xdp_dummy", xdp_program, &opt.ifaces, opt.xdp_mode)?;
+    }
+    if let Mode::Decap | Mode::Node = opt.mode {
+        info!("decap mode");
+        if let Err(e) = BpfLogger::init(&mut xdp_decap_bpf) {
+            // This can happen if you remove all log statements from your eBPF program.
+            warn!("failed to initialize eBPF logger: {}", e);
+        }
+        let xdp_program: &mut Xdp = xdp_decap_bpf.program_mut("xdp_decap").unwrap().try_into()?;
+        xdp_program.load()?;
+        attachments.sync("xdp_decap", xdp_program, &decap_ifaces, opt.xdp_mode)?;
+        // The encap side of a node already registered the endpoints.
+        if !matches!(opt.mode, Mode::Node) {
+            let mut endpoints = EndpointMaps::open_pinned(&opt.pin_path)?;
+            for (dst, intf) in &interface_map{
+                endpoints.add(*dst, *intf)?;
+            }
+        }
+        // Rewriting the inner MAC and sourcing ICMP errors needs the
+        // proxy MAC and gateways, even without xdp_encap on this host.
+        if let Some(proxy_mac) = xdp_decap_bpf.map_mut("PROXYMAC"){
+            let mut proxy_mac: HashMap<_, u8, [u8;6]> = HashMap::try_from(proxy_mac)?;
+            proxy_mac.insert(&0, &proxy_mac_addr, 0)?;
+        } else {
+            warn!("PROXYMAC map not found");
+        }
+        if let Some(nw_map) = xdp_decap_bpf.map_mut("NETWORKS"){
+            let mut nw_map: LpmTrie<_, u32, Network> = LpmTrie::try_from(nw_map)?;
+            for network in &opt.networks{
+                nw_map.insert(&network.key(), network.value(), 0)?;
             }
-            
-        },
-        Mode::Dummy => {
-            info!("dummy mode");
-            if let Err(e) = BpfLogger::init(&mut xdp_dummy_bpf) {
-                // This can happen if you remove all log statements from your eBPF program.
-                warn!("failed to initialize eBPF logger: {}", e);
+        } else {
+            warn!("NETWORKS map not found");
+        }
+        if let Some(ports) = xdp_decap_bpf.map_mut("TUNNELPORTS"){
+            let mut ports: HashMap<_, u8, TunnelPorts> = HashMap::try_from(ports)?;
+            ports.insert(&0, &tunnel_ports, 0)?;
+        } else {
+            warn!("TUNNELPORTS map not found");
+        }
+        local_ips = local_ip_addresses(&attachments, &opt);
+        set_local_ips(&mut xdp_decap_bpf, &local_ips)?;
+        let mut peer_check = 0;
+        if opt.peer_check || !opt.peers.is_empty() {
+            peer_check |= PEER_CHECK_SOURCE;
+        }
+        if opt.check_inner_source {
+            peer_check |= PEER_CHECK_INNER;
+        }
+        // Both maps are pinned and may still hold what an earlier run put
+        // there.
+        if let Some(peers) = xdp_decap_bpf.map_mut("PEERS"){
+            let mut peers: HashMap<_, u32, u32> = HashMap::try_from(peers)?;
+            let stale: Vec<u32> = peers.keys().filter_map(Result::ok).collect();
+            for addr in stale {
+                let _ = peers.remove(&addr);
             }
-            let xdp_program: &mut Xdp = xdp_dummy_bpf.program_mut("xdp_dummy").unwrap().try_into()?;
-            xdp_program.load()?;
-            xdp_program.attach(&opt.iface, XdpFlags::DRV_MODE)
-                .context("failed to attach the XDP program with default flags - try changing XdpFlags::default() to XdpFlags::DRV_MODE")?;           
-        }
-        Mode::Decap => {
-            info!("decap mode");
-            if let Err(e) = BpfLogger::init(&mut xdp_decap_bpf) {
-                // This can happen if you remove all log statements from your eBPF program.
-                warn!("failed to initialize eBPF logger: {}", e);
+            for peer in &opt.peers {
+                peers.insert(u32::from(*peer), u32::from(*peer), 0)?;
             }
-            let xdp_program: &mut Xdp = xdp_decap_bpf.program_mut("xdp_decap").unwrap().try_into()?;
-            xdp_program.load()?;
-            xdp_program.attach(&opt.iface, XdpFlags::DRV_MODE)
-                .context("failed to attach the XDP program with default flags - try changing XdpFlags::default() to XdpFlags::DRV_MODE")?;
-            for (dst, intf) in &interface_map{;
-                if let Some(intf_map) = xdp_decap_bpf.map_mut("INTERFACE"){
-                    let mut intf_map: HashMap<_, u32, Interface> = HashMap::try_from(intf_map)?;
-                    intf_map.insert(dst, intf, 0)?;
-                } else {
-                    warn!("INTERFACE map not found");
-                }
+        } else {
+            warn!("PEERS map not found");
+        }
+        if let Some(check) = xdp_decap_bpf.map_mut("PEERCHECK"){
+            let mut check: HashMap<_, u8, u32> = HashMap::try_from(check)?;
+            if peer_check != 0 {
+                check.insert(0, peer_check, 0)?;
+            } else {
+                let _ = check.remove(&0);
+            }
+        } else {
+            warn!("PEERCHECK map not found");
+        }
+        if opt.feedback{
+            // Remotes weigh paths by the underlay address they send to.
+            let addr = get_interface_ip_address(&opt.phy)
+                .or_else(|| local_ips.first().copied())
+                .context("no local address to send congestion reports from")?;
+            reporter = Some(congestion::Reporter::new(Ipv4Addr::from(addr), opt.feedback_port)?);
+        }
+        if opt.learn{
+            if let Some(learning) = xdp_decap_bpf.map_mut("LEARNING"){
+                let mut learning: HashMap<_, u8, u8> = HashMap::try_from(learning)?;
+                learning.insert(&0, &1, 0)?;
+            } else {
+                warn!("LEARNING map not found");
+            }
+        }
+
+        if let Some(psk_path) = &opt.psk{
+            let psk = tunnel::load_psk(psk_path)?;
+            // The only interface, checked above.
+            let xsk_iface = attachments.interfaces("xdp_decap").next().context("no interface to bind the AF_XDP socket to")?;
+            let dec = Arc::new(Mutex::new(tunnel::Decryptor::new(psk)));
+            for xsk in bind_xsks(&mut xdp_decap_bpf, xsk_iface)? {
+                let dec = dec.clone();
+                let endpoints = EndpointMaps::open_pinned(&opt.pin_path)?;
+                let peers = tunnel::PeerCheck::open_pinned(&opt.pin_path)?;
+                std::thread::spawn(move || {
+                    if let Err(e) = tunnel::run_decrypt(xsk, dec, endpoints, peers, proxy_mac_addr){
+                        warn!("decryption worker failed: {}", e);
+                    }
+                });
             }
-        },
+        }
     }
 
 
@@ -281,69 +649,456 @@ async fn main() -> Result<(), anyhow::Error> {
     
     
 
+    // Encap and decap redirect into veths, whose peers need xdp_dummy.
+    if !matches!(opt.mode, Mode::Dummy) {
+        let xdp_program: &mut Xdp = xdp_dummy_bpf.program_mut("xdp_dummy").unwrap().try_into()?;
+        xdp_program.load()?;
+    }
+
+    attachments.log_status();
     info!("Waiting for Ctrl-C...");
-    signal::ctrl_c().await?;
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    let mut feedback_interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            res = signal::ctrl_c() => {
+                res?;
+                break;
+            }
+            event = next_event(&mut gossip_rx) => {
+                match event {
+                    Some(event) => {
+                        update_peers(&mut xdp_decap_bpf, &opt.peers, &event)?;
+                        apply_gossip_event(&mut xdp_encap_bpf, &mut gossip_owners, event)?;
+                    }
+                    None => gossip_rx = None,
+                }
+            }
+            change = next_event(&mut discovery_rx) => {
+                match change {
+                    Some(change) => {
+                        for workload in &change.removed {
+                            for (ip, _) in workload.endpoints(local_next_hop) {
+                                info!("removing workload {} on ifidx {}", Ipv4Addr::from(ip), workload.ifidx);
+                                interface_map.remove(&ip);
+                            }
+                        }
+                        for workload in &change.added {
+                            for (ip, intf) in workload.endpoints(local_next_hop) {
+                                info!("adding workload {} on ifidx {}", Ipv4Addr::from(ip), workload.ifidx);
+                                interface_map.insert(ip, intf);
+                            }
+                        }
+                        if let Some(announcer) = announcer.as_mut() {
+                            announcer.set_targets(arp_targets(&gateways, &interface_map));
+                        }
+                        match program_workloads(&opt.pin_path, &change, local_next_hop) {
+                            Ok(local_endpoints) => {
+                                if let Some(local) = &gossip_local {
+                                    advertise(local, local_endpoints);
+                                }
+                            }
+                            Err(e) => warn!("failed to program workloads: {:#}", e),
+                        }
+                    }
+                    None => discovery_rx = None,
+                }
+            }
+            feedback = next_event(&mut feedback_rx) => {
+                match feedback {
+                    Some(feedback) if !known_remote(&xdp_encap_bpf, &xdp_decap_bpf, feedback.remote) => {
+                        debug!("ignoring congestion report from unknown {}", feedback.remote);
+                    }
+                    Some(feedback) => {
+                        let weights = xdp_encap_bpf.map_mut("PATHWEIGHT").context("PATHWEIGHT map not found")?;
+                        let mut weights: HashMap<_, u32, PathWeights> = HashMap::try_from(weights)?;
+                        path_weights.apply(&mut weights, feedback)?;
+                    }
+                    None => feedback_rx = None,
+                }
+            }
+            _ = feedback_interval.tick() => {
+                if let Some(reporter) = reporter.as_mut() {
+                    if let Some(stats) = xdp_decap_bpf.map("PATHSTATS") {
+                        let stats: HashMap<_, PathKey, PathStats> = HashMap::try_from(stats)?;
+                        reporter.report(&stats)?;
+                    }
+                }
+                if feedback_rx.is_some() {
+                    if let Some(weights) = xdp_encap_bpf.map_mut("PATHWEIGHT") {
+                        let mut weights: HashMap<_, u32, PathWeights> = HashMap::try_from(weights)?;
+                        path_weights.expire(&mut weights);
+                    }
+                }
+            }
+            _ = interval.tick() => {
+                let programs = match opt.mode {
+                    Mode::Encap => vec![(&mut xdp_encap_bpf, "xdp_encap", &opt.ifaces)],
+                    Mode::Decap => vec![(&mut xdp_decap_bpf, "xdp_decap", &decap_ifaces)],
+                    Mode::Node => vec![
+                        (&mut xdp_encap_bpf, "xdp_encap", &opt.ifaces),
+                        (&mut xdp_decap_bpf, "xdp_decap", &decap_ifaces),
+                    ],
+                    _ => vec![(&mut xdp_dummy_bpf, "xdp_dummy", &opt.ifaces)],
+                };
+                for (bpf, name, ifaces) in programs {
+                    let program: &mut Xdp = bpf.program_mut(name).unwrap().try_into()?;
+                    if let Err(e) = attachments.sync(name, program, ifaces, opt.xdp_mode) {
+                        warn!("failed to update interfaces: {:#}", e);
+                    }
+                }
+                // Only the ports of workloads registered here, DEVMAP may
+                // hold targets other tools put there.
+                if !matches!(opt.mode, Mode::Dummy) {
+                    match EndpointMaps::open_pinned(&opt.pin_path) {
+                        Ok(endpoints) => {
+                            let program: &mut Xdp = xdp_dummy_bpf.program_mut("xdp_dummy").unwrap().try_into()?;
+                            peers.sync(program, &endpoints.local_ports());
+                        }
+                        Err(e) => warn!("failed to read local endpoints: {:#}", e),
+                    }
+                }
+                // The CNI plugin registers workloads without telling us.
+                if let Some(local) = &gossip_local {
+                    match EndpointMaps::open_pinned(&opt.pin_path) {
+                        Ok(endpoints) => advertise(local, endpoints.local()),
+                        Err(e) => warn!("failed to read local endpoints: {:#}", e),
+                    }
+                }
+                // Interfaces and their addresses come and go.
+                if let Mode::Decap | Mode::Node = opt.mode {
+                    let current = local_ip_addresses(&attachments, &opt);
+                    if current != local_ips {
+                        set_local_ips(&mut xdp_decap_bpf, &current)?;
+                        local_ips = current;
+                    }
+                }
+                if let (Mode::Decap | Mode::Node, true) = (&opt.mode, opt.learn) {
+                    if let Some(learned) = xdp_decap_bpf.map_mut("LEARNED") {
+                        let mut learned: HashMap<_, u32, LearnedEndpoint> = HashMap::try_from(learned)?;
+                        let endpoints = learning::age_out(&mut learned, Duration::from_secs(opt.learn_age))?;
+                        if let Some(path) = &opt.learned_file {
+                            if let Err(e) = learning::export(path, &endpoints) {
+                                warn!("failed to export learned endpoints: {}", e);
+                            }
+                        }
+                    }
+                }
+                if let Some(announcer) = announcer.as_mut() {
+                    if let Some(proxy_mac) = xdp_encap_bpf.map("PROXYMAC") {
+                        let proxy_mac: HashMap<_, u8, [u8;6]> = HashMap::try_from(proxy_mac)?;
+                        if let Ok(mac) = proxy_mac.get(&0, 0) {
+                            announcer.update(mac);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if !matches!(opt.mode, Mode::Dummy) {
+        let program: &mut Xdp = xdp_dummy_bpf.program_mut("xdp_dummy").unwrap().try_into()?;
+        peers.detach_all(program);
+        // What we programmed goes with us, the pods the CNI plugin registered
+        // stay for the next run.
+        let cleaned = EndpointMaps::open_pinned(&opt.pin_path).and_then(|mut endpoints| {
+            endpoints.retain(|ip, intf| intf.flags & INTERFACE_FLAG_LOCAL != 0 && !interface_map.contains_key(&ip))?;
+            endpoints.release_ports()
+        });
+        if let Err(e) = cleaned {
+            warn!("failed to remove our endpoints: {:#}", e);
+        }
+    }
     info!("Exiting...");
 
     Ok(())
 }
 
-fn get_interface_index(interface_name: &str) -> Result<u32, Error> {
-    let interface_name_cstring = CString::new(interface_name)?;
-    let interface_index = unsafe { libc::if_nametoindex(interface_name_cstring.as_ptr()) };
-    if interface_index == 0 {
-        Err(Error::new(ErrorKind::NotFound, "Interface not found"))
+// Prints the flows past the elephant threshold from the flow table pinned
+// by a running encap sprayer.
+fn list_elephants(pin_path: &Path) -> Result<(), anyhow::Error> {
+    let path = pin_path.join("FLOWTABLE");
+    let flows = MapData::from_pin(&path)
+        .with_context(|| format!("failed to open {}, is the sprayer running?", path.display()))?;
+    let flows: HashMap<_, FlowKey, FlowNextHop> = HashMap::try_from(Map::LruHashMap(flows))?;
+    for entry in flows.iter() {
+        let (key, flow) = entry?;
+        if flow.flags & FLOW_FLAG_ELEPHANT == 0 {
+            continue;
+        }
+        println!(
+            "{}:{} -> {}:{} proto {} bytes {} path {} via {}",
+            Ipv4Addr::from(u32::from_be(key.src_ip)),
+            u16::from_be(key.src_port),
+            Ipv4Addr::from(u32::from_be(key.dst_ip)),
+            u16::from_be(key.dst_port),
+            key.ip_proto,
+            flow.bytes,
+            flow.path,
+            Ipv4Addr::from(u32::from_be(flow.dst_ip)),
+        );
+    }
+    Ok(())
+}
+
+const FIB_RESULTS: [&str; 9] = [
+    "success",
+    "blackhole",
+    "unreachable",
+    "prohibit",
+    "not forwarded",
+    "forwarding disabled",
+    "unsupported lwt",
+    "no neighbour",
+    "fragmentation needed",
+];
+
+// Prints the counters pinned by running sprayers.
+fn print_stats(pin_path: &Path) -> Result<(), anyhow::Error> {
+    let path = pin_path.join("FIBSTATS");
+    let fib_stats = MapData::from_pin(&path)
+        .with_context(|| format!("failed to open {}, is the sprayer running?", path.display()))?;
+    let fib_stats: PerCpuArray<_, u64> = PerCpuArray::try_from(Map::PerCpuArray(fib_stats))?;
+    println!("underlay fib lookups:");
+    for outcome in 0..MAX_FIB_RESULTS {
+        let count: u64 = fib_stats.get(&outcome, 0)?.iter().sum();
+        let name = match FIB_RESULTS.get(outcome as usize) {
+            Some(name) => name.to_string(),
+            None if outcome == FIB_RESULT_ERROR => "error".to_string(),
+            None if outcome == FIB_RESULT_FRAGMENT => "passed to fragment".to_string(),
+            None => format!("result {}", outcome),
+        };
+        if count > 0 || (outcome as usize) < FIB_RESULTS.len() {
+            println!("  {:<22} {}", name, count);
+        }
+    }
+
+    // Only there once a decap sprayer ran.
+    let path = pin_path.join("SPOOFSTATS");
+    if let Ok(spoof_stats) = MapData::from_pin(&path) {
+        let spoof_stats: PerCpuArray<_, u64> = PerCpuArray::try_from(Map::PerCpuArray(spoof_stats))?;
+        println!("dropped tunnel packets:");
+        for (reason, name) in [(SPOOF_UNKNOWN_PEER, "unknown peer"), (SPOOF_INNER_SOURCE, "foreign inner source")] {
+            let count: u64 = spoof_stats.get(&reason, 0)?.iter().sum();
+            println!("  {:<22} {}", name, count);
+        }
+    }
+    Ok(())
+}
+
+// Points every local workload at the proxy MAC for the gateways and the
+// other endpoints.
+fn arp_targets(gateways: &[u32], workloads: &std::collections::HashMap<u32, Interface>) -> Vec<(u32, Vec<u32>)> {
+    workloads.iter()
+        .map(|(own_ip, intf)| {
+            let ips = gateways.iter().chain(workloads.keys())
+                .filter(|ip| *ip != own_ip)
+                .copied()
+                .collect();
+            (intf.ifidx, ips)
+        })
+        .collect()
+}
+
+// Tunnels end on the addresses of the interfaces we decapsulate on, on the
+// physical interface's, which may be the bridge or bond above them, and on
+// the sources networks use instead.
+fn local_ip_addresses(attachments: &Attachments, opt: &Opt) -> Vec<u32> {
+    let mut addrs: Vec<u32> = attachments.interfaces("xdp_decap")
+        .chain(std::iter::once(opt.phy.as_str()))
+        .filter_map(get_interface_ip_address)
+        .chain(opt.networks.iter().filter_map(|network| network.src.map(u32::from)))
+        .collect();
+    addrs.sort_unstable();
+    addrs.dedup();
+    addrs
+}
+
+// Replaces what LOCALIP holds, including entries pinned by an earlier run.
+fn set_local_ips(bpf: &mut Bpf, addrs: &[u32]) -> Result<(), anyhow::Error> {
+    let local_ip = bpf.map_mut("LOCALIP").context("LOCALIP map not found")?;
+    let mut local_ip: HashMap<_, u32, u8> = HashMap::try_from(local_ip)?;
+    let stale: Vec<u32> = local_ip.keys()
+        .filter_map(Result::ok)
+        .filter(|addr| !addrs.contains(addr))
+        .collect();
+    for addr in stale {
+        let _ = local_ip.remove(&addr);
+    }
+    for addr in addrs {
+        local_ip.insert(addr, 1, 0)?;
+    }
+    if addrs.is_empty() {
+        warn!("no local underlay address, all tunnel traffic is passed to the stack");
     } else {
-        Ok(interface_index)
+        let addrs: Vec<Ipv4Addr> = addrs.iter().map(|addr| Ipv4Addr::from(*addr)).collect();
+        info!("decapsulating tunnels to {:?}", addrs);
     }
+    Ok(())
 }
 
-fn get_mac_addresses_and_interface_indexes() -> Vec<([u8; 6], u32)> {
-    let mut mac_addresses_and_interface_indexes = Vec::new();
-    if let Ok(ifaddrs) = getifaddrs() {
-        for ifaddr in ifaddrs {
-            let interface_index = match get_interface_index(&ifaddr.interface_name) {
-                Ok(index) => index,
-                Err(_) => continue,
-            };
-            if let Some(address) = ifaddr.address {
-                if let Some(link_address) = address.as_link_addr(){
-                    if let Some(mac) = link_address.addr(){
-                        mac_addresses_and_interface_indexes.push((mac, interface_index));
-                    }
-                }
+// Unregisters the workloads that went away and registers the new ones,
+// warning about addresses that fail. Returns the local endpoints afterwards.
+fn program_workloads(pin_path: &Path, change: &discovery::Change, next_hop: u32) -> Result<Vec<u32>, anyhow::Error> {
+    let mut endpoints = EndpointMaps::open_pinned(pin_path)?;
+    for workload in &change.removed {
+        for (ip, _) in workload.endpoints(next_hop) {
+            if let Err(e) = endpoints.remove(ip) {
+                warn!("failed to remove workload {}: {:#}", Ipv4Addr::from(ip), e);
+            }
+        }
+    }
+    for workload in &change.added {
+        for (ip, intf) in workload.endpoints(next_hop) {
+            if let Err(e) = endpoints.add(ip, intf) {
+                warn!("failed to add workload {}: {:#}", Ipv4Addr::from(ip), e);
             }
         }
     }
-    mac_addresses_and_interface_indexes
+    Ok(endpoints.local())
 }
 
-fn get_interface_ip_address(interface_name: &str) -> Option<u32> {
-    if let Ok(ifaddrs) = getifaddrs() {
-        for ifaddr in ifaddrs {
-            if ifaddr.interface_name == interface_name {
-                if let Some(address) = ifaddr.address {
-                    if let Some(ip_address) = address.as_sockaddr_in() {
-                        return Some(ip_address.ip())
-                    }
-                }
-            }
+// Whether `addr` is a sprayer we tunnel to, or accept tunnels from, and so
+// may report congestion on our paths.
+fn known_remote(encap: &Bpf, decap: &Bpf, addr: Ipv4Addr) -> bool {
+    let addr = u32::from(addr);
+    let remote_endpoint = encap.map("INTERFACE")
+        .and_then(|intf_map| HashMap::<_, u32, Interface>::try_from(intf_map).ok())
+        .map(|intf_map| intf_map.iter()
+            .filter_map(Result::ok)
+            .any(|(_, intf)| intf.flags & INTERFACE_FLAG_LOCAL == 0 && intf.next_hop == addr))
+        .unwrap_or(false);
+    let learned = || decap.map("LEARNED")
+        .and_then(|learned| HashMap::<_, u32, LearnedEndpoint>::try_from(learned).ok())
+        .map(|learned| learned.iter()
+            .filter_map(Result::ok)
+            .any(|(_, endpoint)| endpoint.underlay_ip == addr))
+        .unwrap_or(false);
+    let peer = || decap.map("PEERS")
+        .and_then(|peers| HashMap::<_, u32, u32>::try_from(peers).ok())
+        .map(|peers| peers.get(&addr, 0).is_ok())
+        .unwrap_or(false);
+    remote_endpoint || learned() || peer()
+}
+
+// Whether `patterns` can only ever match one interface.
+fn single_interface(patterns: &[String]) -> bool {
+    matches!(patterns, [pattern] if !pattern.contains(['*', '?']))
+}
+
+// An AF_XDP socket for every rx queue of `iface`, each registered in the
+// XSKMAP of `bpf` under its queue: frames are only redirected to the socket
+// of the queue they arrived on.
+fn bind_xsks(bpf: &mut Bpf, iface: &str) -> Result<Vec<XskSocket>, anyhow::Error> {
+    let ifidx = get_interface_index(iface)?;
+    let mut queues = get_rx_queue_count(iface)
+        .with_context(|| format!("failed to count the rx queues of {}", iface))?;
+    if queues > MAX_XSK_QUEUES {
+        warn!("{} has {} rx queues, encrypted traffic on queues past {} is dropped", iface, queues, MAX_XSK_QUEUES);
+        queues = MAX_XSK_QUEUES;
+    }
+    let xsk_map = bpf.map_mut("XSKMAP").context("XSKMAP map not found")?;
+    let mut xsk_map: XskMap<_> = XskMap::try_from(xsk_map)?;
+    let mut xsks = Vec::with_capacity(queues as usize);
+    for queue in 0..queues {
+        let xsk = XskSocket::new(ifidx, queue)
+            .with_context(|| format!("failed to create AF_XDP socket on {} queue {}", iface, queue))?;
+        xsk_map.set(queue, &xsk, 0)?;
+        xsks.push(xsk);
+    }
+    info!("AF_XDP sockets bound to {} rx queues of {}", queues, iface);
+    Ok(xsks)
+}
+
+fn advertise(local: &tokio::sync::watch::Sender<Vec<u32>>, endpoints: Vec<u32>) {
+    local.send_if_modified(|current| {
+        if *current == endpoints {
+            return false;
         }
+        *current = endpoints;
+        true
+    });
+}
+
+async fn next_event<T>(rx: &mut Option<tokio::sync::mpsc::Receiver<T>>) -> Option<T> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
     }
-    None
 }
 
-fn mac_to_vec(mac: &str) -> [u8; 6]{
-    let bytes: Vec<u8> = mac
-        .split(':')
-        .map(|s| u8::from_str_radix(s, 16).unwrap())
+// Accepts tunnels from gossip members, sent from their underlay address or
+// one of their network sources, while they are up. Configured peers stay
+// accepted when they go down.
+fn update_peers(bpf: &mut Bpf, configured: &[Ipv4Addr], event: &gossip::Event) -> Result<(), anyhow::Error> {
+    let peers = bpf.map_mut("PEERS").context("PEERS map not found")?;
+    let mut peers: HashMap<_, u32, u32> = HashMap::try_from(peers)?;
+    let (underlay, sources) = match event {
+        gossip::Event::Endpoints{ underlay, sources, .. } => {
+            (*underlay, std::iter::once(*underlay).chain(sources.iter().copied()).collect())
+        }
+        gossip::Event::Down{ underlay } => (*underlay, Vec::new()),
+    };
+    let stale: Vec<u32> = peers.iter()
+        .filter_map(Result::ok)
+        .filter(|(addr, peer)| *peer == u32::from(underlay) && !sources.contains(&Ipv4Addr::from(*addr)))
+        .map(|(addr, _)| addr)
+        .filter(|addr| !configured.contains(&Ipv4Addr::from(*addr)))
         .collect();
-    let mut mac_addr: [u8; 6] = [0; 6];
-    mac_addr.copy_from_slice(&bytes[..]);
-    mac_addr
+    for addr in stale {
+        let _ = peers.remove(&addr);
+    }
+    for addr in sources {
+        peers.insert(u32::from(addr), u32::from(underlay), 0)?;
+    }
+    Ok(())
 }
 
-fn ip_to_dec(ip: &str) -> u32{
-    let ip_addr: Ipv4Addr = ip.parse().unwrap();
-    u32::from_be_bytes(ip_addr.octets())
-}
\ No newline at end of file
+// Programs the endpoints a remote sprayer advertises, replacing whatever it
+// advertised before, and drops cached flows towards endpoints that are gone.
+// Our own workloads are never touched, whoever claims them.
+fn apply_gossip_event(
+    bpf: &mut Bpf,
+    owners: &mut gossip::Owners,
+    event: gossip::Event,
+) -> Result<(), anyhow::Error> {
+    let intf_map = bpf.map_mut("INTERFACE").context("INTERFACE map not found")?;
+    let mut intf_map: HashMap<_, u32, Interface> = HashMap::try_from(intf_map)?;
+    let gossip::Assignment { underlay, stale, endpoints } = owners.assign(event, |ip| {
+        matches!(intf_map.get(&ip, 0), Ok(intf) if intf.flags & INTERFACE_FLAG_LOCAL != 0)
+    });
+    let next_hop = u32::from_be_bytes(underlay.octets());
+    for ip in &stale {
+        info!("removing endpoint {} behind {}", Ipv4Addr::from(*ip), underlay);
+        let _ = intf_map.remove(ip);
+    }
+    for ip in &endpoints {
+        intf_map.insert(ip, Interface{
+            mac: [0; 6],
+            ifidx: 0,
+            next_hop,
+            flags: 0,
+        }, 0)?;
+    }
+
+    let nh_map = bpf.map_mut("NEXTHOP").context("NEXTHOP map not found")?;
+    let mut nh_map: HashMap<_, u32, u32> = HashMap::try_from(nh_map)?;
+    for ip in &stale {
+        let _ = nh_map.remove(ip);
+    }
+    for ip in &endpoints {
+        nh_map.insert(ip, next_hop, 0)?;
+    }
+
+    if !stale.is_empty() {
+        let flow_table = bpf.map_mut("FLOWTABLE").context("FLOWTABLE map not found")?;
+        let mut flow_table: HashMap<_, FlowKey, FlowNextHop> = HashMap::try_from(flow_table)?;
+        let flows: Vec<FlowKey> = flow_table.keys()
+            .filter_map(|key| key.ok())
+            .filter(|key| stale.contains(&u32::from_be(key.dst_ip)))
+            .collect();
+        for key in flows {
+            let _ = flow_table.remove(&key);
+        }
+    }
+    Ok(())
+}
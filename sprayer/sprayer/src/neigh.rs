@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use aya::maps::perf::AsyncPerfEventArrayBuffer;
+use aya::maps::MapData;
+use bytes::BytesMut;
+use log::{debug, warn};
+
+// A next hop is probed at most this often, however many packets wait for it.
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+// Nothing listens there, the datagram only has to make the kernel resolve the
+// neighbour.
+const DISCARD_PORT: u16 = 9;
+
+/// Reads the next hops xdp_encap found without a neighbour entry from one
+/// CPU's buffer and gets the kernel to resolve them.
+pub async fn resolve(mut buf: AsyncPerfEventArrayBuffer<MapData>) -> Result<(), anyhow::Error> {
+    let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let mut buffers = vec![BytesMut::with_capacity(16); 16];
+    let mut probed: HashMap<Ipv4Addr, Instant> = HashMap::new();
+    loop {
+        let events = buf.read_events(&mut buffers).await?;
+        if events.lost > 0 {
+            debug!("lost {} neighbour requests", events.lost);
+        }
+        for event in buffers.iter().take(events.read) {
+            let next_hop = match event.get(..4).and_then(|bytes| <[u8; 4]>::try_from(bytes).ok()) {
+                Some(next_hop) => Ipv4Addr::from(u32::from_ne_bytes(next_hop)),
+                None => continue,
+            };
+            let now = Instant::now();
+            if probed.get(&next_hop).map_or(false, |last| now - *last < PROBE_INTERVAL) {
+                continue;
+            }
+            probed.insert(next_hop, now);
+            debug!("resolving underlay next hop {}", next_hop);
+            if let Err(e) = sock.send_to(&[], (next_hop, DISCARD_PORT)) {
+                warn!("failed to probe {}: {}", next_hop, e);
+            }
+        }
+        probed.retain(|_, last| last.elapsed() < PROBE_INTERVAL);
+    }
+}
@@ -0,0 +1,94 @@
+use std::ffi::CString;
+use std::io::{Error, ErrorKind};
+
+use nix::ifaddrs::getifaddrs;
+
+pub fn get_interface_index(interface_name: &str) -> Result<u32, Error> {
+    let interface_name_cstring = CString::new(interface_name)?;
+    let interface_index = unsafe { libc::if_nametoindex(interface_name_cstring.as_ptr()) };
+    if interface_index == 0 {
+        Err(Error::new(ErrorKind::NotFound, "Interface not found"))
+    } else {
+        Ok(interface_index)
+    }
+}
+
+pub fn get_mac_addresses_and_interface_indexes() -> Vec<([u8; 6], u32)> {
+    let mut mac_addresses_and_interface_indexes = Vec::new();
+    if let Ok(ifaddrs) = getifaddrs() {
+        for ifaddr in ifaddrs {
+            let interface_index = match get_interface_index(&ifaddr.interface_name) {
+                Ok(index) => index,
+                Err(_) => continue,
+            };
+            if let Some(address) = ifaddr.address {
+                if let Some(link_address) = address.as_link_addr(){
+                    if let Some(mac) = link_address.addr(){
+                        mac_addresses_and_interface_indexes.push((mac, interface_index));
+                    }
+                }
+            }
+        }
+    }
+    mac_addresses_and_interface_indexes
+}
+
+pub fn get_interface_ip_address(interface_name: &str) -> Option<u32> {
+    if let Ok(ifaddrs) = getifaddrs() {
+        for ifaddr in ifaddrs {
+            if ifaddr.interface_name == interface_name {
+                if let Some(address) = ifaddr.address {
+                    if let Some(ip_address) = address.as_sockaddr_in() {
+                        return Some(ip_address.ip())
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Number of receive queues of the interface, as listed in sysfs.
+pub fn get_rx_queue_count(interface_name: &str) -> Result<u32, Error> {
+    let queues = std::fs::read_dir(format!("/sys/class/net/{}/queues", interface_name))?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("rx-"))
+        .count();
+    Ok(queues.max(1) as u32)
+}
+
+/// Whether the interface with index `index` exists, also if that cannot be
+/// told.
+pub fn interface_exists(index: u32) -> bool {
+    nix::net::if_::if_nameindex().map_or(true, |intfs| intfs.iter().any(|intf| intf.index() == index))
+}
+
+/// Names and indexes of the interfaces matching any of `patterns`, which may
+/// use `*` and `?` wildcards, e.g. `veth*`.
+pub fn get_matching_interfaces(patterns: &[String]) -> Result<Vec<(String, u32)>, Error> {
+    let mut interfaces = Vec::new();
+    for intf in nix::net::if_::if_nameindex()?.iter() {
+        let name = intf.name().to_string_lossy();
+        if matches_any(patterns, &name) {
+            interfaces.push((name.into_owned(), intf.index()));
+        }
+    }
+    Ok(interfaces)
+}
+
+/// Whether the interface `name` matches any of `patterns`.
+pub fn matches_any(patterns: &[String], name: &str) -> bool {
+    patterns.iter().any(|pattern| wildcard_match(pattern.as_bytes(), name.as_bytes()))
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            wildcard_match(rest, name) || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name_rest))) => wildcard_match(rest, name_rest),
+        (Some((p, rest)), Some((n, name_rest))) => p == n && wildcard_match(rest, name_rest),
+        _ => false,
+    }
+}
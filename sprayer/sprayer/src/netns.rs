@@ -0,0 +1,36 @@
+use std::fs::File;
+
+use anyhow::{anyhow, Context};
+use nix::sched::{setns, CloneFlags};
+
+/// Runs `f` on a thread that joined `netns`, or right here without one.
+/// Netlink sockets and child processes follow the namespace of the calling
+/// thread, while the process itself stays where it is.
+pub fn in_netns<T: Send>(
+    netns: Option<&File>,
+    f: impl FnOnce() -> Result<T, anyhow::Error> + Send,
+) -> Result<T, anyhow::Error> {
+    match netns {
+        Some(_) => in_netns_thread(netns, f),
+        None => f(),
+    }
+}
+
+/// Like `in_netns`, but always on a thread of its own. A panic on that
+/// thread is returned as an error.
+pub fn in_netns_thread<T: Send>(
+    netns: Option<&File>,
+    f: impl FnOnce() -> Result<T, anyhow::Error> + Send,
+) -> Result<T, anyhow::Error> {
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                if let Some(netns) = netns {
+                    setns(netns, CloneFlags::CLONE_NEWNET).context("failed to enter network namespace")?;
+                }
+                f()
+            })
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("namespace thread panicked")))
+    })
+}
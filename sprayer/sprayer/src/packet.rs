@@ -0,0 +1,51 @@
+use std::io::Error;
+use std::mem::{size_of, zeroed};
+use std::os::fd::RawFd;
+
+/// A send-only AF_PACKET socket. Frames are written as-is, Ethernet header
+/// included, to whatever interface index is given on each send.
+pub struct PacketSocket {
+    fd: RawFd,
+}
+
+impl PacketSocket {
+    pub fn new() -> Result<Self, Error> {
+        // Protocol 0 keeps the kernel from queueing any received frames on
+        // this socket.
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(PacketSocket { fd })
+    }
+
+    pub fn send(&self, ifidx: u32, frame: &[u8]) -> Result<(), Error> {
+        let mut addr: libc::sockaddr_ll = unsafe { zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_ifindex = ifidx as i32;
+        addr.sll_halen = 6;
+        if frame.len() >= 6 {
+            addr.sll_addr[..6].copy_from_slice(&frame[..6]);
+        }
+        let ret = unsafe {
+            libc::sendto(
+                self.fd,
+                frame.as_ptr() as *const libc::c_void,
+                frame.len(),
+                0,
+                &addr as *const _ as *const libc::sockaddr,
+                size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PacketSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
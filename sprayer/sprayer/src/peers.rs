@@ -0,0 +1,201 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aya::programs::{xdp::XdpLinkId, Xdp};
+use futures::stream::TryStreamExt;
+use log::{debug, info, warn};
+use rtnetlink::packet::link::nlas::{Info, InfoKind, Nla};
+use rtnetlink::packet::LinkMessage;
+use sprayer::netns::{in_netns, in_netns_thread};
+
+use crate::attach::VETH_PEER_MODE;
+
+// Targets that were ignored are looked at again after this long, a veth's
+// peer may not have been moved into its namespace yet.
+const IGNORE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The parts of a link we need. `peer_netns` is set when the peer a veth
+// points to with `link_index` lives in another namespace.
+struct Link {
+    ifindex: u32,
+    ifname: String,
+    link_index: Option<u32>,
+    peer_netns: bool,
+    veth: bool,
+}
+
+impl From<LinkMessage> for Link {
+    fn from(msg: LinkMessage) -> Self {
+        let mut link = Link {
+            ifindex: msg.header.index,
+            ifname: String::new(),
+            link_index: None,
+            peer_netns: false,
+            veth: false,
+        };
+        for nla in msg.nlas {
+            match nla {
+                Nla::IfName(name) => link.ifname = name,
+                Nla::Link(idx) => link.link_index = Some(idx),
+                Nla::LinkNetNsId(_) => link.peer_netns = true,
+                Nla::Info(infos) => link.veth = infos.contains(&Info::Kind(InfoKind::Veth)),
+                _ => {}
+            }
+        }
+        link
+    }
+}
+
+struct Peer {
+    netns: Option<File>,
+    ifname: String,
+    link: XdpLinkId,
+}
+
+/// Frames redirected into a veth are only received if its peer runs an XDP
+/// program, so xdp_dummy goes onto the peers of the ports of local endpoints.
+#[derive(Default)]
+pub struct Peers {
+    attached: HashMap<u32, Peer>,
+    // Targets that are no veth or whose peer could not take the program,
+    // and since when.
+    ignored: HashMap<u32, Instant>,
+}
+
+impl Peers {
+    /// Attaches `program` to the peers of the veths among `targets`, entering
+    /// their namespace if needed, and detaches it from targets that are gone.
+    pub fn sync(&mut self, program: &mut Xdp, targets: &[u32]) {
+        let gone: Vec<u32> = self.attached.keys().filter(|target| !targets.contains(target)).copied().collect();
+        for target in gone {
+            if let Some(peer) = self.attached.remove(&target) {
+                detach(program, peer);
+            }
+        }
+        self.ignored.retain(|target, since| targets.contains(target) && since.elapsed() < IGNORE_TIMEOUT);
+        for &target in targets {
+            if self.attached.contains_key(&target) || self.ignored.contains_key(&target) {
+                continue;
+            }
+            let (netns, ifname) = match find_peer(target) {
+                Ok(Some(peer)) => peer,
+                Ok(None) => {
+                    self.ignored.insert(target, Instant::now());
+                    continue;
+                }
+                Err(e) => {
+                    warn!("failed to find the peer of ifidx {}: {:#}", target, e);
+                    continue;
+                }
+            };
+            let attached = in_netns(netns.as_ref(), || {
+                program.attach(&ifname, VETH_PEER_MODE.flags())
+                    .with_context(|| format!("failed to attach xdp_dummy to {}", ifname))
+            });
+            match attached {
+                Ok(link) => {
+                    info!("xdp_dummy attached to {}, the peer of ifidx {}, in {} mode", ifname, target, VETH_PEER_MODE);
+                    self.attached.insert(target, Peer { netns, ifname, link });
+                }
+                Err(e) => {
+                    warn!("{:#}", e);
+                    self.ignored.insert(target, Instant::now());
+                }
+            }
+        }
+    }
+
+    pub fn detach_all(&mut self, program: &mut Xdp) {
+        for (_, peer) in self.attached.drain() {
+            detach(program, peer);
+        }
+    }
+}
+
+fn detach(program: &mut Xdp, peer: Peer) {
+    let Peer { netns, ifname, link } = peer;
+    // Already gone with the namespace when the pod was deleted.
+    match in_netns(netns.as_ref(), || Ok(program.detach(link)?)) {
+        Ok(()) => info!("xdp_dummy detached from {}", ifname),
+        Err(e) => debug!("failed to detach xdp_dummy from {}: {:#}", ifname, e),
+    }
+}
+
+fn find_peer(target: u32) -> Result<Option<(Option<File>, String)>, anyhow::Error> {
+    let links = links(None)?;
+    let veth = match links.iter().find(|link| link.ifindex == target) {
+        Some(link) if link.veth => link,
+        _ => return Ok(None),
+    };
+    let peer_idx = match veth.link_index {
+        Some(idx) => idx,
+        None => return Ok(None),
+    };
+    if !veth.peer_netns {
+        let peer = links.iter().find(|link| link.ifindex == peer_idx);
+        return Ok(peer.map(|peer| (None, peer.ifname.clone())));
+    }
+    // Indexes are per namespace, the peer also has to point back at us.
+    for netns in namespaces()? {
+        let links = match links(Some(&netns)) {
+            Ok(links) => links,
+            Err(e) => {
+                debug!("skipping namespace: {:#}", e);
+                continue;
+            }
+        };
+        if let Some(peer) = links.iter().find(|link| {
+            link.ifindex == peer_idx && link.veth && link.link_index == Some(target)
+        }) {
+            return Ok(Some((Some(netns), peer.ifname.clone())));
+        }
+    }
+    Ok(None)
+}
+
+// Every other network namespace in use, whether held by a process or bound
+// by name under /run/netns.
+fn namespaces() -> Result<Vec<File>, anyhow::Error> {
+    let own = std::fs::metadata("/proc/self/ns/net")?.ino();
+    let mut seen = HashSet::from([own]);
+    let mut namespaces = Vec::new();
+    let procs = std::fs::read_dir("/proc")?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path().join("ns/net"));
+    let named = std::fs::read_dir("/run/netns")
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path());
+    for path in procs.chain(named) {
+        // Processes come and go while we look.
+        let netns = match File::open(&path) {
+            Ok(netns) => netns,
+            Err(_) => continue,
+        };
+        match netns.metadata() {
+            Ok(meta) if seen.insert(meta.ino()) => namespaces.push(netns),
+            _ => {}
+        }
+    }
+    Ok(namespaces)
+}
+
+// The links of `netns`, or of our own namespace without one. The netlink
+// socket is driven by a runtime of its own, as we are called from the tick
+// of the main one.
+fn links(netns: Option<&File>) -> Result<Vec<Link>, anyhow::Error> {
+    in_netns_thread(netns, || {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build()?;
+        runtime.block_on(async {
+            let (connection, handle, _) = rtnetlink::new_connection()?;
+            tokio::spawn(connection);
+            let links: Vec<LinkMessage> = handle.link().get().execute().try_collect().await
+                .context("failed to list links")?;
+            Ok(links.into_iter().map(Link::from).collect())
+        })
+    })
+}
@@ -0,0 +1,589 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context};
+use blake2::digest::Mac;
+use blake2::Blake2sMac256;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce, Tag};
+use aya::maps::{HashMap as BpfHashMap, Map, MapData, MapError};
+use common::{Interface, INTERFACE_FLAG_LOCAL, PEER_CHECK_INNER};
+use sprayer::endpoints::EndpointMaps;
+use log::{debug, warn};
+
+use crate::packet::PacketSocket;
+use crate::xsk::XskSocket;
+
+// Offsets into a tunnel frame as written by xdp_encap: outer Ethernet, IPv4
+// (no options) and UDP header, followed by the payload.
+const IP_OFF: usize = 14;
+const UDP_OFF: usize = IP_OFF + 20;
+const PAYLOAD_OFF: usize = UDP_OFF + 8;
+
+// WireGuard transport data message: type, 3 reserved bytes, receiver index
+// and a little-endian counter, then the ciphertext and the Poly1305 tag.
+const MSG_TYPE_DATA: u8 = 4;
+const MSG_HDR_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+const OVERHEAD: usize = MSG_HDR_LEN + TAG_LEN;
+
+// Offsets into the decrypted inner frame, Ethernet followed by IPv4.
+const INNER_IP_OFF: usize = 14;
+const INNER_MIN_LEN: usize = INNER_IP_OFF + 20;
+
+const ECN_MASK: u8 = 0b11;
+const ECN_NOT_ECT: u8 = 0b00;
+const ECN_ECT1: u8 = 0b01;
+const ECN_ECT0: u8 = 0b10;
+const ECN_CE: u8 = 0b11;
+
+const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (1 << 13);
+const WINDOW_WORDS: usize = 32;
+const WINDOW_SIZE: u64 = (WINDOW_WORDS as u64 - 1) * 64;
+
+pub fn load_psk(path: &Path) -> Result<[u8; 32], anyhow::Error> {
+    let hex = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read pre-shared key {}", path.display()))?;
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        bail!("pre-shared key in {} must be 64 hex characters", path.display());
+    }
+    let mut psk = [0u8; 32];
+    for (i, b) in psk.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)?;
+    }
+    Ok(psk)
+}
+
+// Every sender uses its own key, derived from the shared secret, its underlay
+// address and a random per-run session index. Counters therefore never repeat
+// under the same key, even after a restart.
+fn derive_cipher(psk: &[u8; 32], sender: u32, session: u32) -> ChaCha20Poly1305 {
+    let mut mac = <Blake2sMac256 as Mac>::new_from_slice(psk).expect("blake2s accepts 32 byte keys");
+    mac.update(b"sprayer tunnel");
+    mac.update(&sender.to_be_bytes());
+    mac.update(&session.to_le_bytes());
+    ChaCha20Poly1305::new(&mac.finalize().into_bytes())
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+fn ipv4_checksum(hdr: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in hdr.chunks(2) {
+        sum += u32::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Rewrites the outer IPv4 and UDP length fields after the payload changed by
+// `delta` bytes.
+fn fix_outer_lengths(frame: &mut [u8], delta: isize) {
+    let tot_len = u16::from_be_bytes([frame[IP_OFF + 2], frame[IP_OFF + 3]]) as isize + delta;
+    frame[IP_OFF + 2..IP_OFF + 4].copy_from_slice(&(tot_len as u16).to_be_bytes());
+    frame[IP_OFF + 10..IP_OFF + 12].copy_from_slice(&[0, 0]);
+    let check = ipv4_checksum(&frame[IP_OFF..UDP_OFF]);
+    frame[IP_OFF + 10..IP_OFF + 12].copy_from_slice(&check.to_be_bytes());
+    let udp_len = u16::from_be_bytes([frame[UDP_OFF + 4], frame[UDP_OFF + 5]]) as isize + delta;
+    frame[UDP_OFF + 4..UDP_OFF + 6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    frame[UDP_OFF + 6..UDP_OFF + 8].copy_from_slice(&[0, 0]);
+}
+
+// Recomputes the checksum of the inner IPv4 header.
+fn fix_inner_checksum(inner: &mut [u8], hdr_len: usize) {
+    let hdr = &mut inner[INNER_IP_OFF..INNER_IP_OFF + hdr_len];
+    hdr[10..12].copy_from_slice(&[0, 0]);
+    let check = ipv4_checksum(hdr);
+    hdr[10..12].copy_from_slice(&check.to_be_bytes());
+}
+
+/// Prepares a decrypted inner frame for `intf` the way xdp_decap does for
+/// plain tunnels: RFC 6040 ECN merge with the outer `tos`, TTL decrement and
+/// the workload's MAC from the proxy MAC. False if the frame must be dropped.
+fn forward(inner: &mut [u8], outer_tos: u8, intf: &Interface, proxy_mac: [u8; 6]) -> bool {
+    let hdr_len = match inner.get(INNER_IP_OFF) {
+        Some(vihl) if vihl >> 4 == 4 => (*vihl & 0x0f) as usize * 4,
+        _ => return false,
+    };
+    if hdr_len < 20 || inner.len() < INNER_IP_OFF + hdr_len {
+        return false;
+    }
+    let tos = inner[INNER_IP_OFF + 1];
+    let ecn = match (tos & ECN_MASK, outer_tos & ECN_MASK) {
+        (ECN_NOT_ECT, ECN_CE) => return false,
+        (ECN_NOT_ECT, _) | (ECN_CE, _) => tos & ECN_MASK,
+        (_, ECN_CE) => ECN_CE,
+        (ECN_ECT0, ECN_ECT1) => ECN_ECT1,
+        _ => tos & ECN_MASK,
+    };
+    // Expired packets are dropped, the ICMP error would need sealing again.
+    let ttl = inner[INNER_IP_OFF + 8];
+    if ttl <= 1 {
+        return false;
+    }
+    inner[INNER_IP_OFF + 1] = tos & !ECN_MASK | ecn;
+    inner[INNER_IP_OFF + 8] = ttl - 1;
+    fix_inner_checksum(inner, hdr_len);
+    if intf.mac != [0; 6] {
+        inner[..6].copy_from_slice(&intf.mac);
+    }
+    inner[6..12].copy_from_slice(&proxy_mac);
+    true
+}
+
+fn outer_src(frame: &[u8]) -> u32 {
+    u32::from_be_bytes([frame[IP_OFF + 12], frame[IP_OFF + 13], frame[IP_OFF + 14], frame[IP_OFF + 15]])
+}
+
+/// Sliding window replay filter, following the WireGuard implementation of
+/// RFC 6479.
+struct ReplayWindow {
+    counter: u64,
+    bitmap: [u64; WINDOW_WORDS],
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        ReplayWindow { counter: 0, bitmap: [0; WINDOW_WORDS] }
+    }
+
+    fn validate(&mut self, counter: u64) -> bool {
+        if counter >= REJECT_AFTER_MESSAGES {
+            return false;
+        }
+        let counter = counter + 1;
+        if counter + WINDOW_SIZE < self.counter {
+            return false;
+        }
+        let index = counter >> 6;
+        if counter > self.counter {
+            let current = self.counter >> 6;
+            let top = (index - current).min(WINDOW_WORDS as u64);
+            for i in 1..=top {
+                self.bitmap[((current + i) as usize) & (WINDOW_WORDS - 1)] = 0;
+            }
+            self.counter = counter;
+        }
+        let word = &mut self.bitmap[(index as usize) & (WINDOW_WORDS - 1)];
+        let bit = 1u64 << (counter & 63);
+        let seen = *word & bit != 0;
+        *word |= bit;
+        !seen
+    }
+}
+
+struct Encryptor {
+    psk: [u8; 32],
+    session: u32,
+    counter: u64,
+    ciphers: HashMap<u32, ChaCha20Poly1305>,
+}
+
+impl Encryptor {
+    fn new(psk: [u8; 32]) -> Result<Self, Error> {
+        let mut session = [0u8; 4];
+        let ret = unsafe { libc::getrandom(session.as_mut_ptr() as *mut libc::c_void, session.len(), 0) };
+        if ret != session.len() as isize {
+            return Err(Error::last_os_error());
+        }
+        Ok(Encryptor {
+            psk,
+            session: u32::from_ne_bytes(session),
+            counter: 0,
+            ciphers: HashMap::new(),
+        })
+    }
+
+    fn seal(&mut self, frame: &[u8], out: &mut Vec<u8>) -> Option<()> {
+        if frame.len() < PAYLOAD_OFF || self.counter >= REJECT_AFTER_MESSAGES {
+            return None;
+        }
+        let sender = outer_src(frame);
+        let (psk, session) = (&self.psk, self.session);
+        let cipher = self.ciphers.entry(sender).or_insert_with(|| derive_cipher(psk, sender, session));
+        let counter = self.counter;
+        self.counter += 1;
+
+        out.clear();
+        out.extend_from_slice(&frame[..PAYLOAD_OFF]);
+        out.extend_from_slice(&[MSG_TYPE_DATA, 0, 0, 0]);
+        out.extend_from_slice(&session.to_le_bytes());
+        out.extend_from_slice(&counter.to_le_bytes());
+        out.extend_from_slice(&frame[PAYLOAD_OFF..]);
+        let tag = cipher
+            .encrypt_in_place_detached(&nonce(counter), &[], &mut out[PAYLOAD_OFF + MSG_HDR_LEN..])
+            .ok()?;
+        out.extend_from_slice(&tag);
+        fix_outer_lengths(out, OVERHEAD as isize);
+        Some(())
+    }
+}
+
+struct Session {
+    cipher: ChaCha20Poly1305,
+    window: ReplayWindow,
+}
+
+/// Receive state of every sender session. Shared by the workers of all
+/// queues, as a sender's paths hash onto different ones and a replay must
+/// not get past just by arriving on another queue.
+pub struct Decryptor {
+    psk: [u8; 32],
+    sessions: HashMap<(u32, u32), Session>,
+}
+
+impl Decryptor {
+    pub fn new(psk: [u8; 32]) -> Self {
+        Decryptor { psk, sessions: HashMap::new() }
+    }
+
+    // Returns the range of `buf` holding the decrypted inner frame.
+    fn open(&mut self, buf: &mut [u8]) -> Option<std::ops::Range<usize>> {
+        if buf.len() < PAYLOAD_OFF + OVERHEAD || buf[PAYLOAD_OFF] != MSG_TYPE_DATA {
+            return None;
+        }
+        let hdr = &buf[PAYLOAD_OFF..PAYLOAD_OFF + MSG_HDR_LEN];
+        let session = u32::from_le_bytes(hdr[4..8].try_into().ok()?);
+        let counter = u64::from_le_bytes(hdr[8..16].try_into().ok()?);
+        let sender = outer_src(buf);
+
+        let tag_off = buf.len() - TAG_LEN;
+        let tag = *Tag::from_slice(&buf[tag_off..]);
+        let inner = PAYLOAD_OFF + MSG_HDR_LEN..tag_off;
+
+        // Only authenticated packets create state, so unknown senders cannot
+        // fill the session table.
+        let known = self.sessions.contains_key(&(sender, session));
+        let mut new_session = None;
+        let cipher = if known {
+            &self.sessions[&(sender, session)].cipher
+        } else {
+            new_session.insert(derive_cipher(&self.psk, sender, session))
+        };
+        cipher
+            .decrypt_in_place_detached(&nonce(counter), &[], &mut buf[inner.clone()], &tag)
+            .ok()?;
+        let entry = self.sessions.entry((sender, session)).or_insert_with(|| Session {
+            cipher: new_session.take().expect("cipher derived for new session"),
+            window: ReplayWindow::new(),
+        });
+        if !entry.window.validate(counter) {
+            debug!("replayed counter {} from {:x}/{}", counter, sender, session);
+            return None;
+        }
+        Some(inner)
+    }
+}
+
+/// Encrypts tunnel frames that xdp_encap redirected into `xsk` and sends them
+/// out of the physical interface. Every worker seals under a session of its
+/// own, so their counters never collide.
+pub fn run_encrypt(mut xsk: XskSocket, phy_ifidx: u32, psk: [u8; 32]) -> Result<(), Error> {
+    let sock = PacketSocket::new()?;
+    let mut enc = Encryptor::new(psk)?;
+    let mut out = Vec::with_capacity(4096);
+    loop {
+        xsk.recv(1000, |frame| {
+            if enc.seal(frame, &mut out).is_none() {
+                debug!("dropping frame of {} bytes", frame.len());
+                return;
+            }
+            if let Err(e) = sock.send(phy_ifidx, &out) {
+                warn!("failed to send encrypted frame: {}", e);
+            }
+        })?;
+    }
+}
+
+/// The pinned PEERCHECK and PEERS maps of xdp_decap, so decrypted frames get
+/// the inner source check plain tunnels get.
+pub struct PeerCheck {
+    check: BpfHashMap<MapData, u8, u32>,
+    peers: BpfHashMap<MapData, u32, u32>,
+}
+
+impl PeerCheck {
+    pub fn open_pinned(pin_path: &Path) -> Result<Self, anyhow::Error> {
+        let open = |name: &str| {
+            let path = pin_path.join(name);
+            MapData::from_pin(&path).with_context(|| format!("failed to open {}", path.display()))
+        };
+        Ok(PeerCheck {
+            check: BpfHashMap::try_from(Map::HashMap(open("PEERCHECK")?))?,
+            peers: BpfHashMap::try_from(Map::HashMap(open("PEERS")?))?,
+        })
+    }
+
+    // Whether `inner_src` lives behind the sprayer that sent from
+    // `outer_src`, if that is checked at all.
+    fn inner_source_ok(&self, endpoints: &EndpointMaps<MapData>, inner_src: u32, outer_src: u32) -> bool {
+        let check = self.check.get(&0, 0).unwrap_or(0);
+        if check & PEER_CHECK_INNER == 0 {
+            return true;
+        }
+        let underlay = match self.peers.get(&outer_src, 0) {
+            Ok(underlay) => underlay,
+            Err(MapError::KeyNotFound) => outer_src,
+            Err(_) => return false,
+        };
+        matches!(endpoints.get(inner_src), Ok(Some(intf)) if intf.next_hop == underlay)
+    }
+}
+
+/// Authenticates and decrypts tunnel frames that xdp_decap redirected into
+/// `xsk` and delivers the inner frame to the workload interface owning the
+/// inner destination address, applying the checks xdp_decap applies to plain
+/// tunnels.
+pub fn run_decrypt(
+    mut xsk: XskSocket,
+    dec: Arc<Mutex<Decryptor>>,
+    endpoints: EndpointMaps<MapData>,
+    peers: PeerCheck,
+    proxy_mac: [u8; 6],
+) -> Result<(), Error> {
+    let sock = PacketSocket::new()?;
+    let mut buf = Vec::with_capacity(4096);
+    loop {
+        xsk.recv(1000, |frame| {
+            buf.clear();
+            buf.extend_from_slice(frame);
+            let opened = dec.lock().expect("decryptor lock poisoned").open(&mut buf);
+            let inner = match opened {
+                Some(inner) => inner,
+                None => {
+                    debug!("dropping unauthenticated frame of {} bytes", frame.len());
+                    return;
+                }
+            };
+            let (outer_tos, sender) = (buf[IP_OFF + 1], outer_src(&buf));
+            let inner = &mut buf[inner];
+            if inner.len() < INNER_MIN_LEN || inner[12..14] != [0x08, 0x00] {
+                return;
+            }
+            let src_ip = u32::from_be_bytes([inner[26], inner[27], inner[28], inner[29]]);
+            let dst_ip = u32::from_be_bytes([inner[30], inner[31], inner[32], inner[33]]);
+            if !peers.inner_source_ok(&endpoints, src_ip, sender) {
+                debug!("dropping frame from {:x} not behind {:x}", src_ip, sender);
+                return;
+            }
+            // The pinned map, so endpoints added since we started are found.
+            let intf = match endpoints.get(dst_ip) {
+                Ok(Some(intf)) if intf.flags & INTERFACE_FLAG_LOCAL != 0 => intf,
+                _ => {
+                    debug!("no local interface for {:x}", dst_ip);
+                    return;
+                }
+            };
+            if !forward(inner, outer_tos, &intf, proxy_mac) {
+                debug!("dropping frame for {:x}", dst_ip);
+                return;
+            }
+            if let Err(e) = sock.send(intf.ifidx, inner) {
+                warn!("failed to send decrypted frame: {}", e);
+            }
+        })?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSK: [u8; 32] = [7; 32];
+
+    // An outer frame as xdp_encap writes it, from 192.168.0.2.
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; PAYLOAD_OFF];
+        frame[IP_OFF] = 0x45;
+        let tot_len = (PAYLOAD_OFF - IP_OFF + payload.len()) as u16;
+        frame[IP_OFF + 2..IP_OFF + 4].copy_from_slice(&tot_len.to_be_bytes());
+        frame[IP_OFF + 12..IP_OFF + 16].copy_from_slice(&[192, 168, 0, 2]);
+        let udp_len = (PAYLOAD_OFF - UDP_OFF + payload.len()) as u16;
+        frame[UDP_OFF + 4..UDP_OFF + 6].copy_from_slice(&udp_len.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn seal_open() {
+        let mut enc = Encryptor::new(PSK).unwrap();
+        let mut dec = Decryptor::new(PSK);
+        let payload = b"inner ethernet frame";
+        let mut sealed = Vec::new();
+        enc.seal(&frame(payload), &mut sealed).unwrap();
+        assert_eq!(sealed.len(), PAYLOAD_OFF + payload.len() + OVERHEAD);
+        let tot_len = u16::from_be_bytes([sealed[IP_OFF + 2], sealed[IP_OFF + 3]]) as usize;
+        assert_eq!(tot_len, sealed.len() - IP_OFF);
+        assert_eq!(ipv4_checksum(&sealed[IP_OFF..UDP_OFF]), 0);
+        assert_ne!(&sealed[PAYLOAD_OFF + MSG_HDR_LEN..][..payload.len()], payload);
+
+        let mut buf = sealed.clone();
+        let inner = dec.open(&mut buf).unwrap();
+        assert_eq!(&buf[inner], payload);
+    }
+
+    #[test]
+    fn open_rejects_replay() {
+        let mut enc = Encryptor::new(PSK).unwrap();
+        let mut dec = Decryptor::new(PSK);
+        let mut sealed = Vec::new();
+        enc.seal(&frame(b"once"), &mut sealed).unwrap();
+        assert!(dec.open(&mut sealed.clone()).is_some());
+        assert!(dec.open(&mut sealed.clone()).is_none());
+    }
+
+    #[test]
+    fn open_rejects_forgery() {
+        let mut enc = Encryptor::new(PSK).unwrap();
+        let mut sealed = Vec::new();
+        enc.seal(&frame(b"payload"), &mut sealed).unwrap();
+
+        let mut tampered = sealed.clone();
+        tampered[PAYLOAD_OFF + MSG_HDR_LEN] ^= 1;
+        assert!(Decryptor::new(PSK).open(&mut tampered).is_none());
+
+        // The key is bound to the sender's underlay address.
+        let mut spoofed = sealed.clone();
+        spoofed[IP_OFF + 15] = 3;
+        assert!(Decryptor::new(PSK).open(&mut spoofed).is_none());
+
+        assert!(Decryptor::new([8; 32]).open(&mut sealed.clone()).is_none());
+        assert!(Decryptor::new(PSK).open(&mut sealed[..PAYLOAD_OFF + OVERHEAD - 1].to_vec()).is_none());
+    }
+
+    // An inner IPv4 frame with the given tos and ttl.
+    fn inner(tos: u8, ttl: u8) -> Vec<u8> {
+        let mut inner = vec![0u8; INNER_MIN_LEN + 8];
+        inner[12..14].copy_from_slice(&[0x08, 0x00]);
+        inner[INNER_IP_OFF] = 0x45;
+        inner[INNER_IP_OFF + 1] = tos;
+        inner[INNER_IP_OFF + 8] = ttl;
+        fix_inner_checksum(&mut inner, 20);
+        inner
+    }
+
+    const WORKLOAD: Interface = Interface { mac: [2, 0, 0, 0, 0, 1], ifidx: 3, next_hop: 0, flags: INTERFACE_FLAG_LOCAL };
+    const PROXY_MAC: [u8; 6] = [0xde, 0xad, 0xbe, 0xef, 0xba, 0xbe];
+
+    #[test]
+    fn forward_rewrites_frame() {
+        let mut frame = inner(ECN_ECT0, 64);
+        assert!(forward(&mut frame, 0, &WORKLOAD, PROXY_MAC));
+        assert_eq!(&frame[..6], &WORKLOAD.mac);
+        assert_eq!(&frame[6..12], &PROXY_MAC);
+        assert_eq!(frame[INNER_IP_OFF + 8], 63);
+        assert_eq!(frame[INNER_IP_OFF + 1], ECN_ECT0);
+        assert_eq!(ipv4_checksum(&frame[INNER_IP_OFF..INNER_MIN_LEN]), 0);
+    }
+
+    #[test]
+    fn forward_merges_ecn() {
+        for (tos, outer, merged) in [
+            (0xb8 | ECN_ECT0, ECN_CE, Some(0xb8 | ECN_CE)),
+            (ECN_ECT1, ECN_CE, Some(ECN_CE)),
+            (ECN_ECT0, ECN_ECT1, Some(ECN_ECT1)),
+            (ECN_ECT1, ECN_ECT0, Some(ECN_ECT1)),
+            (ECN_CE, ECN_NOT_ECT, Some(ECN_CE)),
+            (ECN_NOT_ECT, ECN_ECT0, Some(ECN_NOT_ECT)),
+            (ECN_NOT_ECT, ECN_CE, None),
+        ] {
+            let mut frame = inner(tos, 64);
+            let forwarded = forward(&mut frame, outer, &WORKLOAD, PROXY_MAC);
+            assert_eq!(forwarded.then(|| frame[INNER_IP_OFF + 1]), merged, "{:#x} in {:#x}", tos, outer);
+        }
+    }
+
+    #[test]
+    fn forward_drops_invalid() {
+        assert!(!forward(&mut inner(0, 1), 0, &WORKLOAD, PROXY_MAC));
+        assert!(!forward(&mut inner(0, 0), 0, &WORKLOAD, PROXY_MAC));
+        let mut options = inner(0, 64);
+        options[INNER_IP_OFF] = 0x4f;
+        assert!(!forward(&mut options, 0, &WORKLOAD, PROXY_MAC));
+        let mut v6 = inner(0, 64);
+        v6[INNER_IP_OFF] = 0x65;
+        assert!(!forward(&mut v6, 0, &WORKLOAD, PROXY_MAC));
+    }
+
+    #[test]
+    fn replay_window_in_order() {
+        let mut window = ReplayWindow::new();
+        for counter in 0..1000 {
+            assert!(window.validate(counter));
+        }
+        assert!(!window.validate(999));
+        assert!(!window.validate(0));
+    }
+
+    #[test]
+    fn replay_window_reordered() {
+        let mut window = ReplayWindow::new();
+        assert!(window.validate(10));
+        assert!(window.validate(5));
+        assert!(!window.validate(5));
+        assert!(window.validate(0));
+        assert!(!window.validate(10));
+    }
+
+    #[test]
+    fn replay_window_out_of_window() {
+        let mut window = ReplayWindow::new();
+        let top = 10 * WINDOW_SIZE;
+        assert!(window.validate(top));
+        // The oldest counter still inside the window, and the first outside.
+        assert!(window.validate(top - WINDOW_SIZE));
+        assert!(!window.validate(top - WINDOW_SIZE - 1));
+        assert!(!window.validate(0));
+    }
+
+    #[test]
+    fn replay_window_jump() {
+        let mut window = ReplayWindow::new();
+        assert!(window.validate(1));
+        // Jumping far ahead clears the bits of the words it moves past.
+        let far = 1 + 64 * WINDOW_WORDS as u64;
+        assert!(window.validate(far));
+        assert!(!window.validate(far));
+        assert!(!window.validate(1));
+        assert!(window.validate(far - 64));
+    }
+
+    #[test]
+    fn replay_window_exhausted() {
+        let mut window = ReplayWindow::new();
+        assert!(window.validate(REJECT_AFTER_MESSAGES - 1));
+        assert!(!window.validate(REJECT_AFTER_MESSAGES));
+    }
+
+    fn psk_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("sprayer-psk-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn load_psk_hex() {
+        let path = psk_file("valid", &format!("{}\n", "0f".repeat(32)));
+        assert_eq!(load_psk(&path).unwrap(), [0x0f; 32]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn load_psk_invalid() {
+        for (name, contents) in [("short", "0f".repeat(31)), ("long", "0f".repeat(33)), ("nonhex", "zz".repeat(32))] {
+            let path = psk_file(name, &contents);
+            assert!(load_psk(&path).is_err(), "{} accepted", name);
+            std::fs::remove_file(path).unwrap();
+        }
+        assert!(load_psk(Path::new("/nonexistent/sprayer.psk")).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use std::io::{Error, ErrorKind};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use log::warn;
+
+// Definitions from linux/if_xdp.h, which libc does not export yet.
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+
+const XDP_COPY: u16 = 1 << 1;
+const XDP_USE_SG: u16 = 1 << 4;
+// Set on every descriptor of a multi-buffer frame but the last.
+const XDP_PKT_CONTD: u32 = 1 << 0;
+
+const FRAME_SIZE: u32 = 4096;
+const NUM_FRAMES: u32 = 4096;
+const RING_SIZE: u32 = 2048;
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+struct Ring<T> {
+    map: *mut libc::c_void,
+    map_len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    desc: *mut T,
+    mask: u32,
+}
+
+impl<T> Ring<T> {
+    fn mmap(fd: RawFd, off: &XdpRingOffset, pgoff: libc::off_t) -> Result<Self, Error> {
+        let map_len = off.desc as usize + RING_SIZE as usize * size_of::<T>();
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                pgoff,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        let base = map as *mut u8;
+        Ok(Ring {
+            map,
+            map_len,
+            producer: unsafe { base.add(off.producer as usize) } as *const AtomicU32,
+            consumer: unsafe { base.add(off.consumer as usize) } as *const AtomicU32,
+            desc: unsafe { base.add(off.desc as usize) } as *mut T,
+            mask: RING_SIZE - 1,
+        })
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    fn entry(&self, idx: u32) -> *mut T {
+        unsafe { self.desc.add((idx & self.mask) as usize) }
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map, self.map_len) };
+    }
+}
+
+/// A receive-only AF_XDP socket bound to a single interface queue. Frames
+/// redirected into it through an XSKMAP are handed to the caller and then
+/// recycled into the fill ring. Jumbo frames span several chunks, they are
+/// put back together before the caller sees them.
+pub struct XskSocket {
+    fd: RawFd,
+    umem: *mut u8,
+    umem_len: usize,
+    rx: Ring<XdpDesc>,
+    fill: Ring<u64>,
+    // The chunks of a multi-buffer frame received so far.
+    frags: Vec<u8>,
+    // The kernel refuses to bind a UMEM without a completion ring, even if
+    // the socket never transmits.
+    _comp: Ring<u64>,
+}
+
+unsafe impl Send for XskSocket {}
+
+impl XskSocket {
+    pub fn new(ifidx: u32, queue_id: u32) -> Result<Self, Error> {
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let umem_len = (NUM_FRAMES * FRAME_SIZE) as usize;
+        let umem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                umem_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if umem == libc::MAP_FAILED {
+            let err = Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        match Self::setup(fd, umem as *mut u8, umem_len, ifidx, queue_id) {
+            Ok(xsk) => Ok(xsk),
+            Err(e) => {
+                unsafe {
+                    libc::munmap(umem, umem_len);
+                    libc::close(fd);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn setup(fd: RawFd, umem: *mut u8, umem_len: usize, ifidx: u32, queue_id: u32) -> Result<Self, Error> {
+        let reg = XdpUmemReg {
+            addr: umem as u64,
+            len: umem_len as u64,
+            chunk_size: FRAME_SIZE,
+            headroom: 0,
+            flags: 0,
+        };
+        setsockopt(fd, XDP_UMEM_REG, &reg)?;
+        setsockopt(fd, XDP_UMEM_FILL_RING, &RING_SIZE)?;
+        setsockopt(fd, XDP_UMEM_COMPLETION_RING, &RING_SIZE)?;
+        setsockopt(fd, XDP_RX_RING, &RING_SIZE)?;
+
+        let mut off = XdpMmapOffsets::default();
+        let mut optlen = size_of::<XdpMmapOffsets>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut off as *mut _ as *mut libc::c_void,
+                &mut optlen,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let rx = Ring::<XdpDesc>::mmap(fd, &off.rx, XDP_PGOFF_RX_RING)?;
+        let fill = Ring::<u64>::mmap(fd, &off.fr, XDP_UMEM_PGOFF_FILL_RING)?;
+        let comp = Ring::<u64>::mmap(fd, &off.cr, XDP_UMEM_PGOFF_COMPLETION_RING)?;
+
+        // Kernels before 6.6 know no multi-buffer sockets and refuse the flag.
+        if let Err(e) = bind(fd, ifidx, queue_id, XDP_COPY | XDP_USE_SG) {
+            if e.raw_os_error() != Some(libc::EINVAL) {
+                return Err(e);
+            }
+            warn!("AF_XDP socket without multi-buffer support, frames over {} bytes are dropped", FRAME_SIZE);
+            bind(fd, ifidx, queue_id, XDP_COPY)?;
+        }
+
+        let xsk = XskSocket { fd, umem, umem_len, rx, fill, frags: Vec::new(), _comp: comp };
+        // Hand the first half of the UMEM to the kernel for receiving.
+        for frame in 0..RING_SIZE {
+            xsk.refill((frame * FRAME_SIZE) as u64);
+        }
+        Ok(xsk)
+    }
+
+    fn refill(&self, addr: u64) {
+        let prod = self.fill.producer().load(Ordering::Relaxed);
+        unsafe { self.fill.entry(prod).write(addr) };
+        self.fill.producer().store(prod.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Blocks until frames arrive or the timeout (in milliseconds) expires,
+    /// then passes every received frame to `f`.
+    pub fn recv<F: FnMut(&[u8])>(&mut self, timeout: i32, mut f: F) -> Result<usize, Error> {
+        let mut pfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        let ret = unsafe { libc::poll(&mut pfd, 1, timeout) };
+        if ret < 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(err);
+        }
+        let prod = self.rx.producer().load(Ordering::Acquire);
+        let mut cons = self.rx.consumer().load(Ordering::Relaxed);
+        let mut received = 0;
+        while cons != prod {
+            let desc = unsafe { self.rx.entry(cons).read() };
+            let chunk = unsafe {
+                std::slice::from_raw_parts(self.umem.add(desc.addr as usize), desc.len as usize)
+            };
+            if desc.options & XDP_PKT_CONTD != 0 {
+                self.frags.extend_from_slice(chunk);
+            } else if self.frags.is_empty() {
+                f(chunk);
+                received += 1;
+            } else {
+                self.frags.extend_from_slice(chunk);
+                f(&self.frags);
+                self.frags.clear();
+                received += 1;
+            }
+            // Return the chunk the frame was received into.
+            self.refill(desc.addr - desc.addr % FRAME_SIZE as u64);
+            cons = cons.wrapping_add(1);
+        }
+        self.rx.consumer().store(cons, Ordering::Release);
+        Ok(received)
+    }
+}
+
+impl AsRawFd for XskSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for XskSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+            libc::munmap(self.umem as *mut libc::c_void, self.umem_len);
+        }
+    }
+}
+
+fn bind(fd: RawFd, ifidx: u32, queue_id: u32, flags: u16) -> Result<(), Error> {
+    let sxdp = SockaddrXdp {
+        sxdp_family: libc::AF_XDP as u16,
+        sxdp_flags: flags,
+        sxdp_ifindex: ifidx,
+        sxdp_queue_id: queue_id,
+        sxdp_shared_umem_fd: 0,
+    };
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &sxdp as *const _ as *const libc::sockaddr,
+            size_of::<SockaddrXdp>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn setsockopt<T>(fd: RawFd, opt: libc::c_int, val: &T) -> Result<(), Error> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_XDP,
+            opt,
+            val as *const T as *const libc::c_void,
+            size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
@@ -4,10 +4,10 @@
 use aya_bpf::{
     bindings::{xdp_action, self},
     macros::{xdp, map},
-    helpers::{bpf_xdp_adjust_head, bpf_fib_lookup, bpf_redirect},
+    helpers::{bpf_xdp_adjust_head, bpf_xdp_adjust_tail, bpf_xdp_get_buff_len, bpf_fib_lookup, bpf_ktime_get_ns},
     programs::XdpContext,
     cty::c_void,
-    maps::HashMap,
+    maps::{HashMap, LruHashMap, LpmTrie, PerCpuArray, XskMap, DevMapHash, lpm_trie::Key},
 };
 use aya_log_ebpf::info;
 use network_types::{
@@ -15,18 +15,101 @@ use network_types::{
     ip::{Ipv4Hdr, IpProto},
     udp::UdpHdr,
 };
-use core::mem::{self, zeroed, size_of};
-use common::Interface;
+use core::mem::{zeroed, size_of};
+use common::{
+    Network, Interface, LearnedEndpoint, PathKey, PathStats,
+    TunnelPorts, SPRAY_BASE_PORT, MAX_PATHS, MAX_XSK_QUEUES,
+    PEER_CHECK_SOURCE, PEER_CHECK_INNER, SPOOF_UNKNOWN_PEER, SPOOF_INNER_SOURCE, MAX_SPOOF_RESULTS,
+};
+use common::ebpf::{
+    IcmpHdr, ptr_at, ptr_at_mut, decrement_ttl, quotable, write_icmp_error, csum,
+    ICMP_TIME_EXCEEDED, ICMP_QUOTE_LEN,
+};
+
+const TUNNEL_HDR_LEN: usize = EthHdr::LEN + Ipv4Hdr::LEN + UdpHdr::LEN;
+const ECN_MASK: u8 = 0b11;
+const ECN_NOT_ECT: u8 = 0b00;
+const ECN_ECT1: u8 = 0b01;
+const ECN_ECT0: u8 = 0b10;
+const ECN_CE: u8 = 0b11;
 
 #[map(name = "INTERFACE")]
 static mut INTERFACE: HashMap<u32, Interface> =
-    HashMap::<u32, Interface>::with_max_entries(256, 0);
+    HashMap::<u32, Interface>::pinned(256, 0);
 
-#[map(name = "DEVMAP")]
-static mut DEVMAP: HashMap<[u8;6], u32> =
+#[map(name = "MACTABLE")]
+static mut MACTABLE: HashMap<[u8;6], u32> =
     HashMap::<[u8;6], u32>::with_max_entries(10, 0);
 
-#[xdp]
+// Workload ports, keyed by ifindex. Frames for ports the daemon did not
+// program are handed to the stack.
+#[map(name = "DEVMAP")]
+static mut DEVMAP: DevMapHash =
+    DevMapHash::pinned(64, 0);
+
+// Programmed by xdp_encap. The proxy MAC is the gateway MAC workloads see
+// on decapsulated frames and the gateways source our ICMP errors.
+#[map(name = "PROXYMAC")]
+static mut PROXYMAC: HashMap<u8, [u8;6]> =
+    HashMap::<u8, [u8;6]>::pinned(1, 0);
+
+#[map(name = "NETWORKS")]
+static mut NETWORKS: LpmTrie<u32, Network> =
+    LpmTrie::<u32, Network>::pinned(100, bindings::BPF_F_NO_PREALLOC);
+
+#[map(name = "LEARNING")]
+static mut LEARNING: HashMap<u8, u8> =
+    HashMap::<u8, u8>::with_max_entries(1, 0);
+
+// Inner source address -> outer source address of received tunnel packets,
+// pinned so xdp_encap can send return traffic to learned endpoints.
+#[map(name = "LEARNED")]
+static mut LEARNED: LruHashMap<u32, LearnedEndpoint> =
+    LruHashMap::<u32, LearnedEndpoint>::pinned(4096, 0);
+
+// Received tunnel packets and CE marks per remote and spray path, reported
+// back to the remotes by the daemon.
+#[map(name = "PATHSTATS")]
+static mut PATHSTATS: LruHashMap<PathKey, PathStats> =
+    LruHashMap::<PathKey, PathStats>::with_max_entries(4096, 0);
+
+// Tunnel destination ports, shared with xdp_encap.
+#[map(name = "TUNNELPORTS")]
+static mut TUNNELPORTS: HashMap<u8, TunnelPorts> =
+    HashMap::<u8, TunnelPorts>::pinned(1, 0);
+
+// Underlay addresses of this host. Tunnel packets to any other address are
+// not ours to decapsulate.
+#[map(name = "LOCALIP")]
+static mut LOCALIP: HashMap<u32, u8> =
+    HashMap::<u32, u8>::pinned(64, 0);
+
+// PEER_CHECK_* flags, no checks without an entry. Pinned, like PEERS, for
+// the daemon's decryption workers.
+#[map(name = "PEERCHECK")]
+static mut PEERCHECK: HashMap<u8, u32> =
+    HashMap::<u8, u32>::pinned(1, 0);
+
+// Addresses we accept tunnels from, mapped to the underlay address the
+// sending sprayer's endpoints are reached through.
+#[map(name = "PEERS")]
+static mut PEERS: HashMap<u32, u32> =
+    HashMap::<u32, u32>::pinned(1024, 0);
+
+// Dropped tunnel packets per CPU, indexed by SPOOF_*.
+#[map(name = "SPOOFSTATS")]
+static mut SPOOFSTATS: PerCpuArray<u64> =
+    PerCpuArray::<u64>::pinned(MAX_SPOOF_RESULTS, 0);
+
+// AF_XDP sockets of the daemon, indexed by rx queue, which authenticate and
+// decrypt traffic to the crypt tunnel port.
+#[map(name = "XSKMAP")]
+static mut XSKMAP: XskMap =
+    XskMap::with_max_entries(MAX_XSK_QUEUES, 0);
+
+// Jumbo frames arrive as multi-buffer, headers are parsed from the first
+// buffer only.
+#[xdp(frags)]
 pub fn xdp_decap(ctx: XdpContext) -> u32 {
     match try_xdp_decap(ctx) {
         Ok(ret) => ret,
@@ -45,14 +128,50 @@ fn try_xdp_decap(ctx: XdpContext) -> Result<u32, u32> {
         return Ok(xdp_action::XDP_PASS);
     }
     let udp = ptr_at_mut::<UdpHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN).ok_or(xdp_action::XDP_PASS)?;
-    let res = if unsafe { u16::from_be((*udp).dest) } == 3000 {
-        unsafe { bpf_xdp_adjust_head(ctx.ctx, (EthHdr::LEN + Ipv4Hdr::LEN + UdpHdr::LEN) as i32)};
+    let dst_port = unsafe { u16::from_be((*udp).dest) };
+    let ports = tunnel_ports();
+    if dst_port as u32 != ports.crypt && !ports.is_plain(dst_port) {
+        return Ok(xdp_action::XDP_PASS);
+    }
+    if unsafe { LOCALIP.get(&u32::from_be((*ip).dst_addr)) }.is_none() {
+        return Ok(xdp_action::XDP_PASS);
+    }
+    let peer_check = unsafe { PEERCHECK.get(&0) }.copied().unwrap_or(0);
+    if peer_check & PEER_CHECK_SOURCE != 0 && unsafe { PEERS.get(&u32::from_be((*ip).src_addr)) }.is_none() {
+        count_spoofed(SPOOF_UNKNOWN_PEER);
+        return Ok(xdp_action::XDP_DROP);
+    }
+    if dst_port as u32 == ports.crypt {
+        let queue = unsafe { (*ctx.ctx).rx_queue_index };
+        let res = unsafe { XSKMAP.redirect(queue, xdp_action::XDP_DROP as u64) };
+        return Ok(res.unwrap_or(xdp_action::XDP_DROP));
+    }
+    let res = if ports.is_plain(dst_port) {
+        let outer_src = unsafe { (*ip).src_addr };
+        let outer_eth = unsafe { eth.read() };
+        let outer_ip = unsafe { ip.read() };
+        count_path(u32::from_be(outer_src), unsafe { u16::from_be((*udp).source) }, outer_ip.tos);
+        unsafe { bpf_xdp_adjust_head(ctx.ctx, TUNNEL_HDR_LEN as i32)};
         let inner_eth = ptr_at_mut::<EthHdr>(&ctx, 0).ok_or(xdp_action::XDP_PASS)?;
         if unsafe{ (*inner_eth).ether_type } != EtherType::Ipv4 {
             return Ok(xdp_action::XDP_PASS);
         }
         let inner_ip = ptr_at_mut::<Ipv4Hdr>(&ctx, EthHdr::LEN).ok_or(xdp_action::XDP_PASS)?;
         let dst_ip = unsafe { (*inner_ip).dst_addr };
+        if peer_check & PEER_CHECK_INNER != 0 && !behind(unsafe { (*inner_ip).src_addr }, outer_src) {
+            count_spoofed(SPOOF_INNER_SOURCE);
+            return Ok(xdp_action::XDP_DROP);
+        }
+        if unsafe { LEARNING.get(&0) }.is_some() {
+            learn(u32::from_be(unsafe { (*inner_ip).src_addr }), u32::from_be(outer_src));
+        }
+
+        if !merge_ecn(inner_ip, outer_ip.tos) {
+            return Ok(xdp_action::XDP_DROP);
+        }
+        if !decrement_ttl(inner_ip) {
+            return time_exceeded(&ctx, outer_eth, outer_ip);
+        }
 
         let nh_intf = match unsafe { INTERFACE.get(&u32::from_be(dst_ip)) } {
             Some(nh_intf) => {
@@ -63,35 +182,189 @@ fn try_xdp_decap(ctx: XdpContext) -> Result<u32, u32> {
                 return Ok(xdp_action::XDP_ABORTED)
             }
         };
-        unsafe { bpf_redirect(nh_intf.ifidx, 0) }
+        // The workload expects the frame from its gateway, addressed to itself.
+        let mut eth = unsafe { inner_eth.read() };
+        if nh_intf.mac != [0; 6] {
+            eth.dst_addr = nh_intf.mac;
+        }
+        if let Some(proxy_mac) = unsafe { PROXYMAC.get(&0) } {
+            eth.src_addr = *proxy_mac;
+        }
+        unsafe { inner_eth.write(eth) };
+        let res = unsafe { DEVMAP.redirect(nh_intf.ifidx, xdp_action::XDP_PASS as u64) };
+        res.unwrap_or(xdp_action::XDP_PASS)
 
     } else {
-        xdp_action::XDP_PASS.into()
+        xdp_action::XDP_PASS
     };
     //info!(&ctx, "redirect res: {}", res);
-    Ok(res as u32)
+    Ok(res)
 }
 
-#[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
-    unsafe { core::hint::unreachable_unchecked() }
+// Refreshes the timestamp of a known endpoint in place and only writes a new
+// entry when the endpoint is new or moved to another underlay address.
+#[inline(always)]
+fn learn(inner_src: u32, outer_src: u32) {
+    let now = unsafe { bpf_ktime_get_ns() };
+    if let Some(learned) = unsafe { LEARNED.get_ptr_mut(&inner_src) } {
+        if unsafe { (*learned).underlay_ip } == outer_src {
+            unsafe { (*learned).last_seen = now };
+            return;
+        }
+    }
+    let learned = LearnedEndpoint {
+        underlay_ip: outer_src,
+        last_seen: now,
+    };
+    let _ = unsafe { LEARNED.insert(&inner_src, &learned, 0) };
+}
+
+#[inline(always)]
+fn count_path(remote: u32, src_port: u16, outer_tos: u8) {
+    let path = src_port.wrapping_sub(SPRAY_BASE_PORT) as u32;
+    if path >= MAX_PATHS as u32 {
+        return;
+    }
+    let key = PathKey { remote, path };
+    let ce = (outer_tos & ECN_MASK == ECN_CE) as u64;
+    // Updates from different CPUs may race, losing a count now and then is
+    // fine for a ratio.
+    if let Some(stats) = unsafe { PATHSTATS.get_ptr_mut(&key) } {
+        unsafe {
+            (*stats).packets += 1;
+            (*stats).ce += ce;
+        }
+        return;
+    }
+    let stats = PathStats { packets: 1, ce };
+    let _ = unsafe { PATHSTATS.insert(&key, &stats, 0) };
+}
+
+// RFC 6040 normal mode decapsulation: congestion experienced on the outer
+// header is carried over to the inner one, or the frame dropped when the
+// inner transport is not ECN capable.
+#[inline(always)]
+fn merge_ecn(ip_hdr: *mut Ipv4Hdr, outer_tos: u8) -> bool {
+    let tos = unsafe { (*ip_hdr).tos };
+    let ecn = match (tos & ECN_MASK, outer_tos & ECN_MASK) {
+        (ECN_NOT_ECT, ECN_CE) => return false,
+        (ECN_NOT_ECT, _) | (ECN_CE, _) => return true,
+        (_, ECN_CE) => ECN_CE,
+        (ECN_ECT0, ECN_ECT1) => ECN_ECT1,
+        _ => return true,
+    };
+    set_tos(ip_hdr, tos & !ECN_MASK | ecn);
+    true
 }
 
 #[inline(always)]
-fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Option<*const T> {
-    let start = ctx.data();
-    let end = ctx.data_end();
-    let len = mem::size_of::<T>();
+fn set_tos(ip_hdr: *mut Ipv4Hdr, tos: u8) {
+    // tos shares its checksummed 16 bit word with version and ihl.
+    let vihl = unsafe { *(ip_hdr as *const u8) };
+    let old = u16::from_be_bytes([vihl, unsafe { (*ip_hdr).tos }]) as u32;
+    let new = u16::from_be_bytes([vihl, tos]) as u32;
+    // RFC 1624: HC' = ~(~HC + ~m + m')
+    let mut sum = (!u16::from_be(unsafe { (*ip_hdr).check })) as u32 + (!old & 0xffff) + new;
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    unsafe {
+        (*ip_hdr).check = u16::to_be(!(sum as u16));
+        (*ip_hdr).tos = tos;
+    }
+}
 
-    if start + offset + len > end {
-        return None;
+// Answers the decapsulated frame with an ICMP time exceeded from the sender's
+// gateway, tunnelled back to the sprayer it came from.
+#[inline(always)]
+fn time_exceeded(ctx: &XdpContext, outer_eth: EthHdr, outer_ip: Ipv4Hdr) -> Result<u32, u32> {
+    let eth_hdr = ptr_at::<EthHdr>(&ctx, 0).ok_or(xdp_action::XDP_DROP)?;
+    let ip_hdr = ptr_at::<Ipv4Hdr>(&ctx, EthHdr::LEN).ok_or(xdp_action::XDP_DROP)?;
+    if !quotable(ctx, EthHdr::LEN) {
+        return Ok(xdp_action::XDP_DROP);
     }
+    let sender_mac = unsafe { (*eth_hdr).src_addr };
+    let sender = unsafe { (*ip_hdr).src_addr };
+    let gateway = match unsafe { NETWORKS.get(&Key::new(32, sender)) } {
+        Some(network) if network.gateway != 0 => u32::to_be(network.gateway),
+        _ => return Ok(xdp_action::XDP_DROP),
+    };
+    let proxy_mac = match unsafe { PROXYMAC.get(&0) } {
+        Some(proxy_mac) => *proxy_mac,
+        None => return Ok(xdp_action::XDP_DROP),
+    };
 
-    Some((start + offset) as *const T)
+    let grow = TUNNEL_HDR_LEN + Ipv4Hdr::LEN + IcmpHdr::LEN;
+    if unsafe { bpf_xdp_adjust_head(ctx.ctx, -(grow as i32)) } != 0 {
+        return Ok(xdp_action::XDP_DROP);
+    }
+    let len = TUNNEL_HDR_LEN + EthHdr::LEN + Ipv4Hdr::LEN + IcmpHdr::LEN + ICMP_QUOTE_LEN;
+    let delta = len as i32 - unsafe { bpf_xdp_get_buff_len(ctx.ctx) } as i32;
+    if unsafe { bpf_xdp_adjust_tail(ctx.ctx, delta) } != 0 {
+        return Ok(xdp_action::XDP_DROP);
+    }
+
+    let eth = ptr_at_mut::<EthHdr>(&ctx, 0).ok_or(xdp_action::XDP_DROP)?;
+    let ip = ptr_at_mut::<Ipv4Hdr>(&ctx, EthHdr::LEN).ok_or(xdp_action::XDP_DROP)?;
+    let udp = ptr_at_mut::<UdpHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN).ok_or(xdp_action::XDP_DROP)?;
+    unsafe {
+        eth.write(EthHdr {
+            dst_addr: outer_eth.src_addr,
+            src_addr: outer_eth.dst_addr,
+            ether_type: EtherType::Ipv4,
+        });
+        ip.write(Ipv4Hdr {
+            tos: 0,
+            tot_len: u16::to_be((len - EthHdr::LEN) as u16),
+            id: 0,
+            frag_off: 0,
+            ttl: 64,
+            check: 0,
+            src_addr: outer_ip.dst_addr,
+            dst_addr: outer_ip.src_addr,
+            ..outer_ip
+        });
+        let port = u16::to_be(tunnel_ports().base as u16);
+        udp.write(UdpHdr {
+            source: port,
+            dest: port,
+            len: u16::to_be((len - EthHdr::LEN - Ipv4Hdr::LEN) as u16),
+            check: 0,
+        });
+        (*ip).check = csum(ip as *mut u32, Ipv4Hdr::LEN as u32);
+    }
+    write_icmp_error(ctx, TUNNEL_HDR_LEN, ICMP_TIME_EXCEEDED, 0, 0, sender_mac, proxy_mac, gateway, sender)
+        .ok_or(xdp_action::XDP_DROP)?;
+    Ok(xdp_action::XDP_TX)
 }
 
+// Whether the endpoint `inner_src` lives behind the sprayer that sent from
+// `outer_src`, both in network byte order.
 #[inline(always)]
-fn ptr_at_mut<T>(ctx: &XdpContext, offset: usize) -> Option<*mut T> {
-    let ptr = ptr_at::<T>(ctx, offset)?;
-    Some(ptr as *mut T)
+fn behind(inner_src: u32, outer_src: u32) -> bool {
+    let outer_src = u32::from_be(outer_src);
+    let underlay = unsafe { PEERS.get(&outer_src) }.copied().unwrap_or(outer_src);
+    match unsafe { INTERFACE.get(&u32::from_be(inner_src)) } {
+        Some(intf) => intf.next_hop == underlay,
+        None => false,
+    }
+}
+
+#[inline(always)]
+fn count_spoofed(reason: u32) {
+    if let Some(count) = unsafe { SPOOFSTATS.get_ptr_mut(reason) } {
+        unsafe { *count += 1 };
+    }
+}
+
+#[inline(always)]
+fn tunnel_ports() -> TunnelPorts {
+    match unsafe { TUNNELPORTS.get(&0) } {
+        Some(ports) => *ports,
+        None => TunnelPorts::default(),
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    unsafe { core::hint::unreachable_unchecked() }
 }
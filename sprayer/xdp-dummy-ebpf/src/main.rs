@@ -18,7 +18,7 @@ use core::mem::{self, MaybeUninit};
 use core::mem::{size_of, zeroed};
 use aya_bpf::cty::c_void;
 
-#[xdp]
+#[xdp(frags)]
 pub fn xdp_dummy(ctx: XdpContext) -> u32 {
     match try_xdp_dummy(ctx) {
         Ok(ret) => ret,
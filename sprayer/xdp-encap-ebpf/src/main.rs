@@ -2,11 +2,12 @@
 #![no_main]
 
 use aya_bpf::{
-    bindings::{xdp_action, self},
-    macros::{xdp, map},
-    helpers::{bpf_xdp_adjust_head, bpf_fib_lookup, bpf_redirect},
-    programs::{XdpContext, tc},
-    maps::HashMap,
+    bindings::{xdp_action, self, TC_ACT_OK, TC_ACT_SHOT},
+    macros::{xdp, classifier, map},
+    helpers::{bpf_xdp_adjust_head, bpf_xdp_adjust_tail, bpf_xdp_get_buff_len, bpf_fib_lookup, bpf_skb_change_tail, bpf_get_prandom_u32, bpf_ktime_get_ns, bpf_redirect},
+    programs::{XdpContext, TcContext},
+    BpfContext,
+    maps::{HashMap, LruHashMap, LpmTrie, PerCpuArray, PerfEventArray, XskMap, DevMapHash, lpm_trie::Key},
 };
 use aya_log_ebpf::info;
 use network_types::{
@@ -14,10 +15,22 @@ use network_types::{
     ip::{Ipv4Hdr, IpProto, self},
     udp::UdpHdr, tcp::TcpHdr,
 };
-use core::mem::{self, MaybeUninit};
-use core::mem::{size_of, zeroed};
+use core::mem;
+use core::mem::zeroed;
 use aya_bpf::cty::c_void;
-use common::{NetworkKey, Interface, FlowKey, FlowNextHop};
+use common::{
+    Network, Interface, LearnedEndpoint, FlowKey, FlowNextHop, PathWeights, SprayExceptionKey,
+    SPRAY_BASE_PORT, MAX_PATHS, SPRAY_RANDOM, SPRAY_FLOWLET, SPRAY_ROUND_ROBIN, SPRAY_HASH, SPRAY_NONE,
+    DEFAULT_FLOWLET_GAP_US, FLOW_FLAG_ELEPHANT, MAX_FIB_RESULTS, FIB_RESULT_ERROR, FIB_RESULT_FRAGMENT,
+    NETWORK_FLAG_ENCRYPT, NETWORK_FLAG_DSCP, NETWORK_FLAG_FIB_DIRECT, NETWORK_FLAG_ID_COUNTER,
+    OUTER_DF_INHERIT, OUTER_DF_SET, OUTER_DF_CLEAR, INTERFACE_FLAG_LOCAL, TunnelPorts, MAX_XSK_QUEUES,
+};
+// The next hop lookup and outer header code is shared by xdp_encap and
+// tc_encap, which see the packet through PacketCtx.
+use common::ebpf::{
+    PacketCtx, IcmpHdr, ptr_at, ptr_at_mut, decrement_ttl, quotable, write_icmp_error, csum,
+    ICMP_DEST_UNREACH, ICMP_TIME_EXCEEDED, ICMP_HOST_UNREACH, ICMP_FRAG_NEEDED, ICMP_HOST_ANO, ICMP_QUOTE_LEN,
+};
 
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
@@ -50,22 +63,35 @@ impl SrcDst {
     pub const LEN: usize = mem::size_of::<SrcDst>();
 }
 
+const IP_DF: u16 = 0x4000;
+// What encapsulation adds to the inner IP packet on the underlay.
+const ENCAP_OVERHEAD: u16 = (Ipv4Hdr::LEN + UdpHdr::LEN + EthHdr::LEN) as u16;
+const ECN_MASK: u8 = 0b11;
+
+
 #[map(name = "PHYINTF")]
 static mut PHYINTF: HashMap<u8, u32> =
     HashMap::<u8, u32>::with_max_entries(1, 0);
 
 
+// Shared with xdp_decap, which needs the gateways as ICMP source.
 #[map(name = "NETWORKS")]
-static mut NETWORKS: HashMap<NetworkKey, u32> =
-    HashMap::<NetworkKey, u32>::with_max_entries(100, 0);
+static mut NETWORKS: LpmTrie<u32, Network> =
+    LpmTrie::<u32, Network>::pinned(100, bindings::BPF_F_NO_PREALLOC);
 
-#[map(name = "DEVMAP")]
-static mut DEVMAP: HashMap<[u8;6], u32> =
+#[map(name = "MACTABLE")]
+static mut MACTABLE: HashMap<[u8;6], u32> =
     HashMap::<[u8;6], u32>::with_max_entries(10, 0);
 
+// Egress ports, keyed by ifindex. Redirects to ports the daemon did not
+// program are dropped.
+#[map(name = "DEVMAP")]
+static mut DEVMAP: DevMapHash =
+    DevMapHash::pinned(64, 0);
+
 #[map(name = "PROXYMAC")]
 static mut PROXYMAC: HashMap<u8, [u8;6]> =
-    HashMap::<u8, [u8;6]>::with_max_entries(1, 0);
+    HashMap::<u8, [u8;6]>::pinned(1, 0);
 
 #[map(name = "NEXTHOP")]
 static mut NEXTHOP: HashMap<u32, u32> =
@@ -77,13 +103,77 @@ static mut PHYIP: HashMap<u8, u32> =
 
 #[map(name = "INTERFACE")]
 static mut INTERFACE: HashMap<u32, Interface> =
-    HashMap::<u32, Interface>::with_max_entries(256, 0);
+    HashMap::<u32, Interface>::pinned(256, 0);
 
+// Also holds the flowlet state and byte counts, so idle flows are evicted
+// rather than new ones failing to get an entry. Pinned for `sprayer elephants`.
 #[map(name = "FLOWTABLE")]
-static mut FLOWTABLE: HashMap<FlowKey, FlowNextHop> =
-    HashMap::<FlowKey, FlowNextHop>::with_max_entries(256, 0);
+static mut FLOWTABLE: LruHashMap<FlowKey, FlowNextHop> =
+    LruHashMap::<FlowKey, FlowNextHop>::pinned(16384, 0);
+
+// Bytes after which a flow is sprayed, smaller flows stay on their hashed
+// path. Without an entry every flow is sprayed.
+#[map(name = "ELEPHANT")]
+static mut ELEPHANT: HashMap<u8, u64> =
+    HashMap::<u8, u64>::with_max_entries(1, 0);
+
+// Endpoints learned by xdp_decap, used when INTERFACE has no entry.
+#[map(name = "LEARNED")]
+static mut LEARNED: LruHashMap<u32, LearnedEndpoint> =
+    LruHashMap::<u32, LearnedEndpoint>::pinned(4096, 0);
+
+// Number of spray paths, each a different outer UDP source port the fabric
+// hashes onto its own ECMP route.
+#[map(name = "LINKS")]
+static mut LINKS: HashMap<u8, u32> =
+    HashMap::<u8, u32>::with_max_entries(1, 0);
 
-#[xdp]
+// Remote underlay address -> path weights from the congestion feedback of
+// that remote. Remotes without feedback are sprayed evenly.
+#[map(name = "PATHWEIGHT")]
+static mut PATHWEIGHT: HashMap<u32, PathWeights> =
+    HashMap::<u32, PathWeights>::with_max_entries(1024, 0);
+
+// Spray policies overriding a network's for some protocols and ports.
+#[map(name = "SPRAYEXCEPT")]
+static mut SPRAYEXCEPT: HashMap<SprayExceptionKey, u32> =
+    HashMap::<SprayExceptionKey, u32>::with_max_entries(64, 0);
+
+// Next round robin path of each CPU.
+#[map(name = "RRCOUNTER")]
+static mut RRCOUNTER: PerCpuArray<u32> =
+    PerCpuArray::<u32>::with_max_entries(1, 0);
+
+// Tunnel destination ports, shared with xdp_decap.
+#[map(name = "TUNNELPORTS")]
+static mut TUNNELPORTS: HashMap<u8, TunnelPorts> =
+    HashMap::<u8, TunnelPorts>::pinned(1, 0);
+
+// Next outer IP ID of each CPU, for networks numbering their own.
+#[map(name = "IPID")]
+static mut IPID: PerCpuArray<u32> =
+    PerCpuArray::<u32>::with_max_entries(1, 0);
+
+// Outcomes of the underlay fib lookups per CPU, indexed by
+// BPF_FIB_LKUP_RET_*.
+#[map(name = "FIBSTATS")]
+static mut FIBSTATS: PerCpuArray<u64> =
+    PerCpuArray::<u64>::pinned(MAX_FIB_RESULTS, 0);
+
+// Underlay next hops without a neighbour entry, for the daemon to resolve.
+#[map(name = "NEIGHREQ")]
+static mut NEIGHREQ: PerfEventArray<u32> =
+    PerfEventArray::<u32>::with_max_entries(0, 0);
+
+// AF_XDP sockets of the daemon, indexed by rx queue. Frames of encrypted
+// networks are handed to userspace here instead of leaving through PHYINTF.
+#[map(name = "XSKMAP")]
+static mut XSKMAP: XskMap =
+    XskMap::with_max_entries(MAX_XSK_QUEUES, 0);
+
+// Jumbo frames arrive as multi-buffer, headers are parsed from the first
+// buffer only.
+#[xdp(frags)]
 pub fn xdp_encap(ctx: XdpContext) -> u32 {
     let phy_intf = match unsafe { PHYINTF.get(&0) } {
         Some(phy_intf) => {
@@ -101,6 +191,9 @@ pub fn xdp_encap(ctx: XdpContext) -> u32 {
 }
 
 fn try_xdp_encap(ctx: XdpContext, phy_intf: u32) -> Result<u32, u32> {
+    if let Some(res) = switch_local(&ctx, phy_intf) {
+        return res;
+    }
     let flow_next_hop = match get_v4_next_hop_from_flow_table(&ctx) {
         Some(fnh) => {
             fnh
@@ -115,6 +208,9 @@ fn try_xdp_encap(ctx: XdpContext, phy_intf: u32) -> Result<u32, u32> {
                         FnhOrResult::Result(res) => {
                             return res;
                         }
+                        FnhOrResult::IcmpError(icmp_type, code, rest) => {
+                            return icmp_error(&ctx, icmp_type, code, rest);
+                        }
                     }
                 }
                 None => {
@@ -125,10 +221,126 @@ fn try_xdp_encap(ctx: XdpContext, phy_intf: u32) -> Result<u32, u32> {
         }
     };
 
+    if let Some(res) = expire(&ctx) {
+        return res;
+    }
     let res = write_outer_hdr(&ctx, flow_next_hop);
     res
 }
 
+// Encapsulates what the host itself routes into the overlay, on egress of a
+// device the overlay prefixes are routed to. Frames of encrypted networks
+// are dropped, only xdp_encap can hand them to the encryption worker.
+#[classifier]
+pub fn tc_encap(ctx: TcContext) -> i32 {
+    match try_tc_encap(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+fn try_tc_encap(ctx: TcContext) -> Result<i32, i32> {
+    let phy_intf = match unsafe { PHYINTF.get(&0) } {
+        Some(phy_intf) => *phy_intf,
+        None => return Ok(TC_ACT_SHOT),
+    };
+    ctx.pull_data((EthHdr::LEN + Ipv4Hdr::LEN + SrcDst::LEN) as u32).map_err(|_| TC_ACT_OK)?;
+    let eth_hdr = ptr_at::<EthHdr>(&ctx, 0).ok_or(TC_ACT_OK)?;
+    if unsafe { (*eth_hdr).ether_type } != EtherType::Ipv4 {
+        return Ok(TC_ACT_OK);
+    }
+    let flow_next_hop = match get_v4_next_hop_from_flow_table(&ctx) {
+        Some(fnh) => fnh,
+        // The kernel checks the skb against the MTU itself, GSO included.
+        None => match lookup_v4(&ctx, phy_intf, false) {
+            Some(FnhOrResult::Fnh(fnh)) => fnh,
+            Some(FnhOrResult::Result(Ok(xdp_action::XDP_PASS))) => return Ok(TC_ACT_OK),
+            Some(FnhOrResult::IcmpError(icmp_type, code, rest)) => {
+                return tc_icmp_error(&ctx, icmp_type, code, rest);
+            }
+            _ => return Ok(TC_ACT_SHOT),
+        },
+    };
+    // The overlay is a hop, as it is for xdp_encap.
+    let ip_hdr = ptr_at_mut::<Ipv4Hdr>(&ctx, EthHdr::LEN).ok_or(TC_ACT_SHOT)?;
+    if !decrement_ttl(ip_hdr) {
+        return tc_icmp_error(&ctx, ICMP_TIME_EXCEEDED, 0, 0);
+    }
+    let (outer_eth, outer_ip, outer_udp, encrypt) =
+        outer_headers(&ctx, &flow_next_hop).ok_or(TC_ACT_SHOT)?;
+    if encrypt {
+        return Ok(TC_ACT_SHOT);
+    }
+    // The device has no neighbours, the inner addresses are the proxy's.
+    let mut inner_eth = unsafe { eth_hdr.read() };
+    if let Some(proxy_mac) = unsafe { PROXYMAC.get(&0) } {
+        inner_eth.src_addr = *proxy_mac;
+        inner_eth.dst_addr = *proxy_mac;
+    }
+
+    // Room for the outer IP and UDP headers and the inner Ethernet header,
+    // behind the Ethernet header that becomes the outer one.
+    let flags = bindings::BPF_F_ADJ_ROOM_ENCAP_L3_IPV4 as u64
+        | bindings::BPF_F_ADJ_ROOM_ENCAP_L4_UDP as u64
+        | bindings::BPF_F_ADJ_ROOM_ENCAP_L2_ETH as u64
+        | (EthHdr::LEN as u64) << 56;
+    ctx.adjust_room(
+        (Ipv4Hdr::LEN + UdpHdr::LEN + EthHdr::LEN) as i32,
+        bindings::bpf_adj_room_mode::BPF_ADJ_ROOM_MAC,
+        flags,
+    ).map_err(|_| TC_ACT_SHOT)?;
+
+    let eth_ptr = ptr_at_mut::<EthHdr>(&ctx, 0).ok_or(TC_ACT_SHOT)?;
+    let ip_ptr = ptr_at_mut::<Ipv4Hdr>(&ctx, EthHdr::LEN).ok_or(TC_ACT_SHOT)?;
+    let udp_ptr = ptr_at_mut::<UdpHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN).ok_or(TC_ACT_SHOT)?;
+    let inner_eth_ptr = ptr_at_mut::<EthHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN + UdpHdr::LEN).ok_or(TC_ACT_SHOT)?;
+    unsafe {
+        eth_ptr.write(outer_eth);
+        ip_ptr.write(outer_ip);
+        (*ip_ptr).check = csum(ip_ptr as *mut u32, Ipv4Hdr::LEN as u32);
+        udp_ptr.write(outer_udp);
+        inner_eth_ptr.write(inner_eth);
+    }
+    Ok(unsafe { bpf_redirect(flow_next_hop.ifidx, 0) } as i32)
+}
+
+// Turns the skb into an ICMP error from the gateway of the destination's
+// network and hands it back to the host on ingress of the same device.
+#[inline(always)]
+fn tc_icmp_error(ctx: &TcContext, icmp_type: u8, code: u8, rest: u32) -> Result<i32, i32> {
+    ctx.pull_data((EthHdr::LEN + ICMP_QUOTE_LEN) as u32).map_err(|_| TC_ACT_SHOT)?;
+    let eth_hdr = ptr_at::<EthHdr>(ctx, 0).ok_or(TC_ACT_SHOT)?;
+    let ip_hdr = ptr_at::<Ipv4Hdr>(ctx, EthHdr::LEN).ok_or(TC_ACT_SHOT)?;
+    if !quotable(ctx, EthHdr::LEN) {
+        return Ok(TC_ACT_SHOT);
+    }
+    let sender_mac = unsafe { (*eth_hdr).src_addr };
+    let sender = unsafe { (*ip_hdr).src_addr };
+    let gateway = match unsafe { NETWORKS.get(&Key::new(32, (*ip_hdr).dst_addr)) } {
+        Some(network) if network.gateway != 0 => u32::to_be(network.gateway),
+        _ => return Ok(TC_ACT_SHOT),
+    };
+    let proxy_mac = match unsafe { PROXYMAC.get(&0) } {
+        Some(proxy_mac) => *proxy_mac,
+        None => return Ok(TC_ACT_SHOT),
+    };
+
+    // Cut down to the quote first, which also ends GSO, then make room for
+    // the new IP and ICMP headers in front of it.
+    let len = EthHdr::LEN + ICMP_QUOTE_LEN;
+    if unsafe { bpf_skb_change_tail(ctx.skb.skb, len as u32, 0) } != 0 {
+        return Ok(TC_ACT_SHOT);
+    }
+    ctx.adjust_room(
+        (Ipv4Hdr::LEN + IcmpHdr::LEN) as i32,
+        bindings::bpf_adj_room_mode::BPF_ADJ_ROOM_MAC,
+        0,
+    ).map_err(|_| TC_ACT_SHOT)?;
+    write_icmp_error(ctx, 0, icmp_type, code, rest, sender_mac, proxy_mac, gateway, sender).ok_or(TC_ACT_SHOT)?;
+    let ifindex = unsafe { (*ctx.skb.skb).ifindex };
+    Ok(unsafe { bpf_redirect(ifindex, bindings::BPF_F_INGRESS as u64) } as i32)
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
@@ -137,23 +349,62 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
 enum FnhOrResult{
     Fnh(FlowNextHop),
     Result(Result<u32,u32>),
+    // The underlay can't take the packet: ICMP type, code and the rest of
+    // the ICMP header to answer the sender with.
+    IcmpError(u8, u8, u32),
 }
 
+// Destinations on this host are switched straight to their port without
+// encapsulation: endpoints flagged local in INTERFACE, or frames addressed to
+// the MAC of a host interface other than the physical one.
 #[inline(always)]
-fn get_v4_next_hop_from_flow_table(ctx: &XdpContext) -> Option<FlowNextHop>{
-    
-    let ipv4_proto_ptr = ptr_at::<u8>(&ctx, EthHdr::LEN + 9)?;
-    let ipv4_src_dst_port_ptr = ptr_at::<SrcDst>(&ctx, EthHdr::LEN + 12)?;
-
-    let mut flow_key: FlowKey = unsafe { zeroed() };
+fn switch_local(ctx: &XdpContext, phy_intf: u32) -> Option<Result<u32, u32>> {
+    let eth_hdr = ptr_at_mut::<EthHdr>(&ctx, 0)?;
+    if unsafe { (*eth_hdr).ether_type } != EtherType::Ipv4 {
+        return None;
+    }
+    let ip_hdr_ptr = ptr_at::<Ipv4Hdr>(&ctx, EthHdr::LEN)?;
+    let dst_ip = u32::from_be(unsafe { (*ip_hdr_ptr).dst_addr });
+
+    let mut eth = unsafe { eth_hdr.read() };
+    let mut routed = false;
+    let ifidx = match unsafe { INTERFACE.get(&dst_ip) } {
+        Some(intf) if intf.flags & INTERFACE_FLAG_LOCAL != 0 => {
+            eth.dst_addr = intf.mac;
+            routed = true;
+            intf.ifidx
+        }
+        _ => match unsafe { MACTABLE.get(&eth.dst_addr) } {
+            Some(ifidx) => *ifidx,
+            None => return None,
+        },
+    };
+    if ifidx == phy_intf {
+        return None;
+    }
+    // Addressed to the port it came in on, which is the host's business and
+    // must not be tunnelled to ourselves.
+    if ifidx == unsafe { (*ctx.ctx).ingress_ifindex } {
+        return Some(Ok(xdp_action::XDP_PASS));
+    }
+    if routed {
+        if let Some(res) = expire(ctx) {
+            return Some(res);
+        }
+    }
+    if let Some(proxy_mac) = unsafe { PROXYMAC.get(&0) } {
+        eth.src_addr = *proxy_mac;
+    }
+    unsafe { eth_hdr.write(eth) };
 
-    flow_key.dst_ip = unsafe { (*ipv4_src_dst_port_ptr).dst_ip };
-    flow_key.src_ip = unsafe { (*ipv4_src_dst_port_ptr).src_ip };
-    flow_key.dst_port = unsafe { (*ipv4_src_dst_port_ptr).dst_port };
-    flow_key.src_port = unsafe { (*ipv4_src_dst_port_ptr).src_port };
-    flow_key.ip_proto = unsafe { *ipv4_proto_ptr };
+    let res = unsafe { DEVMAP.redirect(ifidx, xdp_action::XDP_DROP as u64) };
+    Some(Ok(res.unwrap_or(xdp_action::XDP_DROP)))
+}
 
-    let x = unsafe { FLOWTABLE.get(&flow_key) };
+#[inline(always)]
+fn get_v4_next_hop_from_flow_table<C: PacketCtx + BpfContext>(ctx: &C) -> Option<FlowNextHop>{
+    
+    let flow_key = get_flow_key(ctx)?;
 
     match unsafe { FLOWTABLE.get(&flow_key) } {
         Some(fnh) => {
@@ -168,6 +419,21 @@ fn get_v4_next_hop_from_flow_table(ctx: &XdpContext) -> Option<FlowNextHop>{
     
 }
 
+#[inline(always)]
+fn get_flow_key(ctx: &impl PacketCtx) -> Option<FlowKey> {
+    let ipv4_proto_ptr = ptr_at::<u8>(&ctx, EthHdr::LEN + 9)?;
+    let ipv4_src_dst_port_ptr = ptr_at::<SrcDst>(&ctx, EthHdr::LEN + 12)?;
+
+    let mut flow_key: FlowKey = unsafe { zeroed() };
+
+    flow_key.dst_ip = unsafe { (*ipv4_src_dst_port_ptr).dst_ip };
+    flow_key.src_ip = unsafe { (*ipv4_src_dst_port_ptr).src_ip };
+    flow_key.dst_port = unsafe { (*ipv4_src_dst_port_ptr).dst_port };
+    flow_key.src_port = unsafe { (*ipv4_src_dst_port_ptr).src_port };
+    flow_key.ip_proto = unsafe { *ipv4_proto_ptr };
+    Some(flow_key)
+}
+
 #[inline(always)]
 fn get_next_hop(ctx: &XdpContext, phy_intf: u32) -> Option<FnhOrResult> {
     
@@ -183,14 +449,21 @@ fn get_next_hop(ctx: &XdpContext, phy_intf: u32) -> Option<FnhOrResult> {
                 let dst_addr = u32::from_be(unsafe{ (*arp_hdr).tpa });
                 let src_addr = u32::from_be(unsafe{ (*arp_hdr).spa });
                 let src_mac = unsafe { (*arp_hdr).sha };
-                let intf = match unsafe { INTERFACE.get(&dst_addr) } {
-                    Some(intf) => intf,
+                // Duplicate address probes and announcements are left alone.
+                if dst_addr == src_addr || src_addr == 0 {
+                    return Some(FnhOrResult::Result(Ok(xdp_action::XDP_PASS)));
+                }
+                if !is_proxied(dst_addr) {
+                    info!(ctx, "intf not found");
+                    return Some(FnhOrResult::Result(Ok(xdp_action::XDP_DROP)));
+                }
+                let pm = match unsafe { PROXYMAC.get(&0) } {
+                    Some(pm) => *pm,
                     None => {
-                        info!(ctx, "intf not found");
+                        info!(ctx, "proxy mac not found");
                         return Some(FnhOrResult::Result(Ok(xdp_action::XDP_DROP)));
                     }
                 };
-                let pm = intf.mac;
                 outer_eth_hdr.src_addr = pm;
                 outer_eth_hdr.dst_addr = src_mac;
                 unsafe { (*arp_hdr).oper = u16::from_be(2) };
@@ -205,120 +478,338 @@ fn get_next_hop(ctx: &XdpContext, phy_intf: u32) -> Option<FnhOrResult> {
             return Some(FnhOrResult::Result(Ok(xdp_action::XDP_PASS)));
         },
         EtherType::Ipv4 => {
-            let ip_hdr_ptr = ptr_at_mut::<Ipv4Hdr>(&ctx, EthHdr::LEN)?;
-            let ip_proto = unsafe { (*ip_hdr_ptr).proto };
-            let src_dst_port = match ip_proto {
-                IpProto::Tcp => {
-                    let tcp_hdr_ptr = ptr_at_mut::<TcpHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
-                    let src_port = unsafe { (*tcp_hdr_ptr).source };
-                    let dst_port = unsafe { (*tcp_hdr_ptr).dest };
-                    Some((src_port, dst_port))
-                },
-                IpProto::Udp => {
-                    let udp_hdr_ptr = ptr_at_mut::<UdpHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
-                    let src_port = unsafe { (*udp_hdr_ptr).source };
-                    let dst_port = unsafe { (*udp_hdr_ptr).dest };
-                    Some((src_port, dst_port))
-                },
-                IpProto::Icmp => None,
-                _ => {
-                    let ipp = ip_proto as u8;
-                    info!(ctx,"ip_proto not tcp or udp, {} passing", ipp);
-                    return Some(FnhOrResult::Result(Ok(xdp_action::XDP_PASS)));
-                }
-            };
-            let dst_ip = unsafe { (*ip_hdr_ptr).dst_addr };
-            let if_idx = unsafe { (*ctx.ctx).ingress_ifindex };
+            return lookup_v4(ctx, phy_intf, true);
+        },
+        _ => {
+            return Some(FnhOrResult::Result(Ok(xdp_action::XDP_PASS)));
+        },
+    };
+    
+    return None
+}
 
-            let nh = match unsafe { INTERFACE.get(&u32::from_be(dst_ip)) } {
-                Some(nh) => {
-                    nh.next_hop.clone()
-                }
-                None => {
-                    info!(ctx, "nh not found");
-                    return Some(FnhOrResult::Result(Ok(xdp_action::XDP_ABORTED)));
-                }
-            };
-            let phy_ip = match unsafe { PHYIP.get(&0) } {
-                Some(phy_ip) => {
-                    phy_ip.clone()
-                }
-                None => {
-                    return Some(FnhOrResult::Result(Ok(xdp_action::XDP_ABORTED)));
-                }
-            };
-            let mut params: bindings::bpf_fib_lookup = unsafe { zeroed() };
-            params.family = 2;
-            params.ifindex = phy_intf;
-            params.__bindgen_anon_4.ipv4_dst = u32::from_be(nh);
-            let params_ptr: *mut bindings::bpf_fib_lookup = &mut params as *mut _;
-
-            let param_size = size_of::<bindings::bpf_fib_lookup>();        
-            let ctx_ptr = ctx.ctx as *mut _ as *mut c_void;
-            let ret: i64 = unsafe {
-                bpf_fib_lookup(ctx_ptr, params_ptr, 64, 0)
-            };
-            if ret != 0 {
-                info!(ctx,"fib lookup failed for dst ip {:i}, ifidx {}, ret {}",dst_ip, if_idx, ret);
+// Resolves the underlay next hop of an IPv4 packet and caches it in the flow
+// table for TCP and UDP. With `check_mtu` the lookup also checks the
+// encapsulated packet against the MTU, skbs leave that to the kernel.
+#[inline(always)]
+fn lookup_v4<C: PacketCtx + BpfContext>(ctx: &C, phy_intf: u32, check_mtu: bool) -> Option<FnhOrResult> {
+    let ip_hdr_ptr = ptr_at_mut::<Ipv4Hdr>(&ctx, EthHdr::LEN)?;
+    let ip_proto = unsafe { (*ip_hdr_ptr).proto };
+    let src_dst_port = match ip_proto {
+        IpProto::Tcp => {
+            let tcp_hdr_ptr = ptr_at_mut::<TcpHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
+            let src_port = unsafe { (*tcp_hdr_ptr).source };
+            let dst_port = unsafe { (*tcp_hdr_ptr).dest };
+            Some((src_port, dst_port))
+        },
+        IpProto::Udp => {
+            let udp_hdr_ptr = ptr_at_mut::<UdpHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN)?;
+            let src_port = unsafe { (*udp_hdr_ptr).source };
+            let dst_port = unsafe { (*udp_hdr_ptr).dest };
+            Some((src_port, dst_port))
+        },
+        IpProto::Icmp => None,
+        _ => {
+            let ipp = ip_proto as u8;
+            info!(ctx,"ip_proto not tcp or udp, {} passing", ipp);
+            return Some(FnhOrResult::Result(Ok(xdp_action::XDP_PASS)));
+        }
+    };
+    let dst_ip = unsafe { (*ip_hdr_ptr).dst_addr };
+
+    let nh = match unsafe { INTERFACE.get(&u32::from_be(dst_ip)) } {
+        Some(nh) => {
+            nh.next_hop.clone()
+        }
+        None => match unsafe { LEARNED.get(&u32::from_be(dst_ip)) } {
+            Some(learned) => learned.underlay_ip,
+            None => {
+                info!(ctx, "nh not found");
                 return Some(FnhOrResult::Result(Ok(xdp_action::XDP_ABORTED)));
             }
-            let flow_next_hop = FlowNextHop{
-                dst_ip: unsafe { params.__bindgen_anon_4.ipv4_dst },
-                src_ip: u32::to_be(phy_ip),
-                dst_mac: params.dmac,
-                src_mac: params.smac,
-                ifidx: params.ifindex,
-            };
-
-            if let Some((src_port, dst_port)) = src_dst_port {
-                let mut flow_key: FlowKey = unsafe { zeroed() };
-                flow_key.dst_ip = unsafe { (*ip_hdr_ptr).dst_addr };
-                flow_key.src_ip = unsafe { (*ip_hdr_ptr).src_addr };
-                flow_key.dst_port = dst_port;
-                flow_key.src_port = src_port;
-                flow_key.ip_proto = unsafe { (*ip_hdr_ptr).proto as u8 };
-                unsafe { FLOWTABLE.insert(&flow_key, &flow_next_hop, 0) };
+        },
+    };
+    let phy_ip = match unsafe { PHYIP.get(&0) } {
+        Some(phy_ip) => {
+            phy_ip.clone()
+        }
+        None => {
+            return Some(FnhOrResult::Result(Ok(xdp_action::XDP_ABORTED)));
+        }
+    };
+    // Tunnels of a network may have their own underlay table and source.
+    let (src_ip, fib_flags, fib_table) = match unsafe { NETWORKS.get(&Key::new(32, dst_ip)) } {
+        Some(network) => {
+            let src_ip = if network.src != 0 { network.src } else { phy_ip };
+            let mut fib_flags = 0;
+            if network.flags & NETWORK_FLAG_FIB_DIRECT != 0 {
+                fib_flags |= bindings::BPF_FIB_LOOKUP_DIRECT;
+                if network.fib_table != 0 {
+                    fib_flags |= bindings::BPF_FIB_LOOKUP_TBID;
+                }
             }
+            (src_ip, fib_flags, network.fib_table)
+        }
+        None => (phy_ip, 0, 0),
+    };
+    let mut params: bindings::bpf_fib_lookup = unsafe { zeroed() };
+    params.family = 2;
+    params.ifindex = phy_intf;
+    params.__bindgen_anon_3.ipv4_src = u32::to_be(src_ip);
+    params.__bindgen_anon_4.ipv4_dst = u32::from_be(nh);
+    params.__bindgen_anon_5.tbid = fib_table;
+    if check_mtu {
+        params.__bindgen_anon_1.tot_len = u16::from_be(unsafe { (*ip_hdr_ptr).tot_len }).saturating_add(ENCAP_OVERHEAD);
+    }
+    let params_ptr: *mut bindings::bpf_fib_lookup = &mut params as *mut _;
 
-            return Some(FnhOrResult::Fnh(flow_next_hop));
+    let ctx_ptr = ctx.as_ptr();
+    let ret: i64 = unsafe {
+        bpf_fib_lookup(ctx_ptr, params_ptr, 64, fib_flags)
+    };
+    let outcome = if ret < 0 { FIB_RESULT_ERROR } else { ret as u32 };
+    if let Some(count) = unsafe { FIBSTATS.get_ptr_mut(outcome) } {
+        unsafe { *count += 1 };
+    }
+    match ret as u32 {
+        bindings::BPF_FIB_LKUP_RET_SUCCESS => {},
+        // Nothing resolves the next hop if we keep dropping, the daemon
+        // makes the kernel look for it.
+        bindings::BPF_FIB_LKUP_RET_NO_NEIGH => {
+            unsafe { NEIGHREQ.output(ctx, &nh, 0) };
+            return Some(FnhOrResult::Result(Ok(xdp_action::XDP_DROP)));
+        },
+        bindings::BPF_FIB_LKUP_RET_FRAG_NEEDED => {
+            // Without DF the kernel may fragment it, leave it to the stack.
+            if unsafe { (*ip_hdr_ptr).frag_off } & u16::to_be(IP_DF) == 0 {
+                if let Some(count) = unsafe { FIBSTATS.get_ptr_mut(FIB_RESULT_FRAGMENT) } {
+                    unsafe { *count += 1 };
+                }
+                return Some(FnhOrResult::Result(Ok(xdp_action::XDP_PASS)));
+            }
+            let mtu = unsafe { params.__bindgen_anon_1.mtu_result }.saturating_sub(ENCAP_OVERHEAD);
+            return Some(FnhOrResult::IcmpError(ICMP_DEST_UNREACH, ICMP_FRAG_NEEDED, u32::to_be(mtu as u32)));
+        },
+        bindings::BPF_FIB_LKUP_RET_BLACKHOLE | bindings::BPF_FIB_LKUP_RET_UNREACHABLE => {
+            return Some(FnhOrResult::IcmpError(ICMP_DEST_UNREACH, ICMP_HOST_UNREACH, 0));
+        },
+        bindings::BPF_FIB_LKUP_RET_PROHIBIT => {
+            return Some(FnhOrResult::IcmpError(ICMP_DEST_UNREACH, ICMP_HOST_ANO, 0));
         },
         _ => {
+            info!(ctx,"fib lookup failed for dst ip {:i}, ret {}",dst_ip, ret);
             return Some(FnhOrResult::Result(Ok(xdp_action::XDP_PASS)));
         },
+    }
+    let flow_next_hop = FlowNextHop{
+        dst_ip: unsafe { params.__bindgen_anon_4.ipv4_dst },
+        src_ip: u32::to_be(src_ip),
+        dst_mac: params.dmac,
+        src_mac: params.smac,
+        ifidx: params.ifindex,
+        path: 0,
+        flags: 0,
+        last_seen: 0,
+        bytes: 0,
     };
-    
-    return None
+
+    if let Some((src_port, dst_port)) = src_dst_port {
+        let mut flow_key: FlowKey = unsafe { zeroed() };
+        flow_key.dst_ip = unsafe { (*ip_hdr_ptr).dst_addr };
+        flow_key.src_ip = unsafe { (*ip_hdr_ptr).src_addr };
+        flow_key.dst_port = dst_port;
+        flow_key.src_port = src_port;
+        flow_key.ip_proto = unsafe { (*ip_hdr_ptr).proto as u8 };
+        unsafe { FLOWTABLE.insert(&flow_key, &flow_next_hop, 0) };
+    }
+
+    Some(FnhOrResult::Fnh(flow_next_hop))
 }
 
+// The proxy MAC answers for every known overlay endpoint and for the gateway
+// of the network the address belongs to.
 #[inline(always)]
-fn write_outer_hdr(ctx: &XdpContext, flow_next_hop: FlowNextHop) -> Result<u32,u32> {
+fn is_proxied(addr: u32) -> bool {
+    if unsafe { INTERFACE.get(&addr) }.is_some() || unsafe { LEARNED.get(&addr) }.is_some() {
+        return true;
+    }
+    match unsafe { NETWORKS.get(&Key::new(32, u32::to_be(addr))) } {
+        Some(network) => network.gateway == addr,
+        None => false,
+    }
+}
+
+// Decrements the TTL of an IPv4 frame we route. Once it runs out the frame
+// is answered with an ICMP time exceeded instead and the result is returned.
+#[inline(always)]
+fn expire(ctx: &XdpContext) -> Option<Result<u32, u32>> {
+    let ip_hdr = ptr_at_mut::<Ipv4Hdr>(&ctx, EthHdr::LEN)?;
+    if decrement_ttl(ip_hdr) {
+        return None;
+    }
+    Some(time_exceeded(ctx))
+}
+
+#[inline(always)]
+fn time_exceeded(ctx: &XdpContext) -> Result<u32, u32> {
+    icmp_error(ctx, ICMP_TIME_EXCEEDED, 0, 0)
+}
+
+// Turns the frame into an ICMP error from the sender's gateway and sends it
+// back out of the port it came in on.
+#[inline(always)]
+fn icmp_error(ctx: &XdpContext, icmp_type: u8, code: u8, rest: u32) -> Result<u32, u32> {
+    let eth_hdr = ptr_at::<EthHdr>(&ctx, 0).ok_or(xdp_action::XDP_DROP)?;
+    let ip_hdr = ptr_at::<Ipv4Hdr>(&ctx, EthHdr::LEN).ok_or(xdp_action::XDP_DROP)?;
+    if !quotable(ctx, EthHdr::LEN) {
+        return Ok(xdp_action::XDP_DROP);
+    }
+    let sender_mac = unsafe { (*eth_hdr).src_addr };
+    let sender = unsafe { (*ip_hdr).src_addr };
+    let gateway = match unsafe { NETWORKS.get(&Key::new(32, sender)) } {
+        Some(network) if network.gateway != 0 => u32::to_be(network.gateway),
+        _ => return Ok(xdp_action::XDP_DROP),
+    };
+    let proxy_mac = match unsafe { PROXYMAC.get(&0) } {
+        Some(proxy_mac) => *proxy_mac,
+        None => return Ok(xdp_action::XDP_DROP),
+    };
+
+    if unsafe { bpf_xdp_adjust_head(ctx.ctx, -((Ipv4Hdr::LEN + IcmpHdr::LEN) as i32)) } != 0 {
+        return Ok(xdp_action::XDP_DROP);
+    }
+    let len = EthHdr::LEN + Ipv4Hdr::LEN + IcmpHdr::LEN + ICMP_QUOTE_LEN;
+    let delta = len as i32 - unsafe { bpf_xdp_get_buff_len(ctx.ctx) } as i32;
+    if unsafe { bpf_xdp_adjust_tail(ctx.ctx, delta) } != 0 {
+        return Ok(xdp_action::XDP_DROP);
+    }
+    write_icmp_error(ctx, 0, icmp_type, code, rest, sender_mac, proxy_mac, gateway, sender).ok_or(xdp_action::XDP_DROP)?;
+    Ok(xdp_action::XDP_TX)
+}
+
+// Builds the outer headers for a packet to `flow_next_hop`, choosing the
+// spray path on the way. Also tells whether the network is encrypted.
+#[inline(always)]
+fn outer_headers(ctx: &impl PacketCtx, flow_next_hop: &FlowNextHop) -> Option<(EthHdr, Ipv4Hdr, UdpHdr, bool)> {
     let new_eth_hdr = EthHdr{
         dst_addr: flow_next_hop.dst_mac,
         src_addr: flow_next_hop.src_mac,
         ether_type: EtherType::Ipv4,
     };
-    let ip_hdr = ptr_at_mut::<Ipv4Hdr>(&ctx, EthHdr::LEN).ok_or(xdp_action::XDP_DROP)?;
-    let new_ip_hdr_tot_len = u16::from_be( unsafe { (*ip_hdr).tot_len }) + (EthHdr::LEN  + Ipv4Hdr::LEN + UdpHdr::LEN) as u16;
+    let ip_hdr = ptr_at_mut::<Ipv4Hdr>(&ctx, EthHdr::LEN)?;
+    let network = unsafe { NETWORKS.get(&Key::new(32, (*ip_hdr).dst_addr)) };
+    let encrypt = match network {
+        Some(network) => network.flags & NETWORK_FLAG_ENCRYPT != 0,
+        None => false,
+    };
+    let remote = u32::from_be(flow_next_hop.dst_ip);
+    let (policy, flowlet_gap) = match network {
+        Some(network) => (network.spray, network.flowlet_gap),
+        None => (SPRAY_RANDOM, DEFAULT_FLOWLET_GAP_US),
+    };
+    let flow_key = get_flow_key(ctx);
+    let flow = flow_key.as_ref().and_then(|key| unsafe { FLOWTABLE.get_ptr_mut(key) });
+    let mouse = is_mouse(flow, u16::from_be(unsafe { (*ip_hdr).tot_len }));
+    let exception = network.and_then(|network| spray_exception(network, &flow_key));
+    let policy = match exception {
+        Some(policy) => policy,
+        // Mice keep to one path, policy none already does without hashing.
+        None if mouse && policy != SPRAY_NONE => SPRAY_HASH,
+        None => policy,
+    };
+    let path = match policy {
+        SPRAY_FLOWLET => flowlet_path(flow, remote, flowlet_gap),
+        SPRAY_ROUND_ROBIN => round_robin_path(),
+        SPRAY_HASH => hash_path(&flow_key),
+        SPRAY_NONE => 0,
+        _ => select_path(remote),
+    };
+    // RFC 6040 normal mode: the outer ECN field is a copy of the inner one.
+    let inner_tos = unsafe { (*ip_hdr).tos };
+    let tos = match network {
+        Some(network) if network.flags & NETWORK_FLAG_DSCP != 0 => {
+            (network.dscp as u8) << 2 | inner_tos & ECN_MASK
+        }
+        _ => inner_tos,
+    };
+    let inner_ttl = unsafe { (*ip_hdr).ttl };
+    let ttl = match network {
+        Some(network) if network.ttl != 0 => network.ttl as u8,
+        _ => inner_ttl,
+    };
+    let inner_frag_off = unsafe { (*ip_hdr).frag_off };
+    // Offset and MF belong to the inner packet, only DF carries over.
+    let frag_off = match network.map_or(OUTER_DF_INHERIT, |network| network.df) {
+        OUTER_DF_SET => u16::to_be(IP_DF),
+        OUTER_DF_CLEAR => 0,
+        _ => inner_frag_off & u16::to_be(IP_DF),
+    };
+    let id = match network {
+        Some(network) if network.flags & NETWORK_FLAG_ID_COUNTER != 0 => u16::to_be(next_ip_id()),
+        _ => unsafe { (*ip_hdr).id },
+    };
+    // The whole inner frame including all fragments, not just the linear part.
+    let inner_len = ctx.len() as u16;
+    let new_ip_hdr_tot_len = inner_len + (Ipv4Hdr::LEN + UdpHdr::LEN) as u16;
     let new_udp_hdr_len = new_ip_hdr_tot_len - Ipv4Hdr::LEN as u16;
     let new_ip_header = Ipv4Hdr{
         _bitfield_1: unsafe { (*ip_hdr)._bitfield_1 },
         _bitfield_align_1: unsafe{ (*ip_hdr)._bitfield_align_1 },
-        tos: unsafe { (*ip_hdr).tos },
-        frag_off: unsafe { (*ip_hdr).frag_off },
+        tos,
+        frag_off,
         tot_len: u16::to_be(new_ip_hdr_tot_len),
-        id: unsafe { (*ip_hdr).id },
-        ttl: unsafe { (*ip_hdr).ttl },
+        id,
+        ttl,
         proto: IpProto::Udp,
-        check: unsafe{ (*ip_hdr).check },
+        check: 0,
         src_addr: flow_next_hop.src_ip,
         dst_addr: flow_next_hop.dst_ip,
     };
     let new_udp_header = UdpHdr{
-        source: u16::to_be(1000),
-        dest: u16::to_be(3000),
+        source: u16::to_be(SPRAY_BASE_PORT + path as u16),
+        dest: u16::to_be(tunnel_port(encrypt, path)),
         len: u16::to_be(new_udp_hdr_len),
         check: 0,
     };
+    Some((new_eth_hdr, new_ip_header, new_udp_header, encrypt))
+}
+
+// Encrypted tunnels all go to the port of the decryption worker, plain ones
+// to a port of the path.
+#[inline(always)]
+fn tunnel_port(encrypt: bool, path: u32) -> u16 {
+    let ports = match unsafe { TUNNELPORTS.get(&0) } {
+        Some(ports) => *ports,
+        None => TunnelPorts::default(),
+    };
+    if encrypt {
+        return ports.crypt as u16;
+    }
+    (ports.base + path % ports.count.max(1)) as u16
+}
+
+// Outer IP IDs from a per-CPU counter, started at a random value so the CPUs
+// and restarts don't repeat each other's IDs right away.
+#[inline(always)]
+fn next_ip_id() -> u16 {
+    let next = match unsafe { IPID.get_ptr_mut(0) } {
+        Some(next) => next,
+        None => return 0,
+    };
+    unsafe {
+        if *next == 0 {
+            *next = bpf_get_prandom_u32() | 1;
+        }
+        let id = *next as u16;
+        *next = (*next).wrapping_add(1);
+        id
+    }
+}
+
+#[inline(always)]
+fn write_outer_hdr(ctx: &XdpContext, flow_next_hop: FlowNextHop) -> Result<u32,u32> {
+    let (new_eth_hdr, new_ip_header, new_udp_header, encrypt) =
+        outer_headers(ctx, &flow_next_hop).ok_or(xdp_action::XDP_DROP)?;
 
     unsafe {
         bpf_xdp_adjust_head(ctx.ctx, -((EthHdr::LEN + Ipv4Hdr::LEN + UdpHdr::LEN) as i32));
@@ -327,48 +818,170 @@ fn write_outer_hdr(ctx: &XdpContext, flow_next_hop: FlowNextHop) -> Result<u32,u
     let outer_eth_hdr_ptr = ptr_at_mut::<EthHdr>(&ctx, 0).ok_or(xdp_action::XDP_DROP)?;
     unsafe { outer_eth_hdr_ptr.write(new_eth_hdr) };
     let outer_ip_ptr = ptr_at_mut::<Ipv4Hdr>(&ctx, EthHdr::LEN).ok_or(xdp_action::XDP_DROP)?;
-    unsafe { outer_ip_ptr.write(new_ip_header); };
+    unsafe {
+        outer_ip_ptr.write(new_ip_header);
+        (*outer_ip_ptr).check = csum(outer_ip_ptr as *mut u32, Ipv4Hdr::LEN as u32);
+    };
     let outer_udp_ptr = ptr_at_mut::<UdpHdr>(&ctx, EthHdr::LEN + Ipv4Hdr::LEN).ok_or(xdp_action::XDP_DROP)?;
     unsafe { outer_udp_ptr.write(new_udp_header); };
 
-    let res = unsafe { bpf_redirect(flow_next_hop.ifidx, 0) };
+    if encrypt {
+        // Without a socket on this queue the frame is dropped rather than
+        // sent in the clear.
+        let queue = unsafe { (*ctx.ctx).rx_queue_index };
+        let res = unsafe { XSKMAP.redirect(queue, xdp_action::XDP_DROP as u64) };
+        return Ok(res.unwrap_or(xdp_action::XDP_DROP));
+    }
+
+    let res = unsafe { DEVMAP.redirect(flow_next_hop.ifidx, xdp_action::XDP_DROP as u64) };
 
-    Ok(res as u32)
+    Ok(res.unwrap_or(xdp_action::XDP_DROP))
 
 }
 
+// Picks a random path, retrying while the path's weight rejects it so hot
+// paths get a share of the traffic proportional to their weight.
 #[inline(always)]
-fn mask(ip: u32, cidr_length: u8) -> u32 {
-    if cidr_length >= 32 {
-        return ip; // No need to mask if CIDR length is 32 or greater
+fn select_path(remote: u32) -> u32 {
+    let links = links();
+    if links <= 1 {
+        return 0;
     }
-
-    let mask: u32 = 0xFFFFFFFFu32.wrapping_shl(32 - u32::from(cidr_length));
-    ip & mask
+    let start = unsafe { bpf_get_prandom_u32() } % links;
+    let weights = match unsafe { PATHWEIGHT.get(&remote) } {
+        Some(weights) => weights,
+        None => return start,
+    };
+    for i in 0..MAX_PATHS as u32 {
+        if i >= links {
+            break;
+        }
+        let mut path = start + i;
+        if path >= links {
+            path -= links;
+        }
+        let weight = match weights.weights.get(path as usize) {
+            Some(weight) => *weight as u32,
+            None => break,
+        };
+        if unsafe { bpf_get_prandom_u32() } & 0xff < weight {
+            return path;
+        }
+    }
+    start
 }
 
+// Keeps a flow on its path while its packets follow each other closely, a
+// pause longer than the gap lets the next flowlet take a fresh path without
+// reordering the previous one.
 #[inline(always)]
-fn uninit<T>() -> *mut T {
-    let mut v: MaybeUninit<T> = MaybeUninit::uninit();
-    v.as_mut_ptr() as *mut T
+fn flowlet_path(flow: Option<*mut FlowNextHop>, remote: u32, gap_us: u32) -> u32 {
+    let flow = match flow {
+        Some(flow) => flow,
+        // Flows without ports are not tracked.
+        None => return select_path(remote),
+    };
+    let now = unsafe { bpf_ktime_get_ns() };
+    let path = if now.saturating_sub(unsafe { (*flow).last_seen }) > gap_us as u64 * 1000 {
+        let path = select_path(remote);
+        unsafe { (*flow).path = path };
+        path
+    } else {
+        unsafe { (*flow).path }
+    };
+    unsafe { (*flow).last_seen = now };
+    path
 }
 
+// Counts the packet against its flow and tells whether the flow is still
+// below the elephant threshold. Untracked flows are never mice.
 #[inline(always)]
-fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Option<*const T> {
-    let start = ctx.data();
-    let end = ctx.data_end();
-    let len = mem::size_of::<T>();
+fn is_mouse(flow: Option<*mut FlowNextHop>, len: u16) -> bool {
+    let flow = match flow {
+        Some(flow) => flow,
+        None => return false,
+    };
+    let bytes = unsafe { (*flow).bytes } + len as u64;
+    unsafe { (*flow).bytes = bytes };
+    let threshold = match unsafe { ELEPHANT.get(&0) } {
+        Some(threshold) => *threshold,
+        None => return false,
+    };
+    if bytes < threshold {
+        return true;
+    }
+    unsafe { (*flow).flags |= FLOW_FLAG_ELEPHANT };
+    false
+}
 
-    if start + offset + len > end {
-        return None;
+#[inline(always)]
+fn links() -> u32 {
+    match unsafe { LINKS.get(&0) } {
+        Some(links) => (*links).min(MAX_PATHS as u32),
+        None => 1,
     }
+}
+
+#[inline(always)]
+fn round_robin_path() -> u32 {
+    let links = links();
+    let next = match unsafe { RRCOUNTER.get_ptr_mut(0) } {
+        Some(next) => next,
+        None => return 0,
+    };
+    let path = unsafe { *next } % links.max(1);
+    unsafe { *next = path + 1 };
+    path
+}
 
-    Some((start + offset) as *const T)
+// Flows without ports only hash their addresses and protocol.
+#[inline(always)]
+fn hash_path(flow_key: &Option<FlowKey>) -> u32 {
+    let links = links();
+    let flow_key = match flow_key {
+        Some(flow_key) if links > 1 => flow_key,
+        _ => return 0,
+    };
+    let ports = if has_ports(flow_key.ip_proto) {
+        (flow_key.src_port as u32) << 16 | flow_key.dst_port as u32
+    } else {
+        0
+    };
+    let mut hash = flow_key.src_ip ^ flow_key.dst_ip.rotate_left(16) ^ ports ^ flow_key.ip_proto as u32;
+    // murmur3 finalizer
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^= hash >> 16;
+    hash % links
 }
 
 #[inline(always)]
-fn ptr_at_mut<T>(ctx: &XdpContext, offset: usize) -> Option<*mut T> {
-    let ptr = ptr_at::<T>(ctx, offset)?;
-    Some(ptr as *mut T)
+fn has_ports(ip_proto: u8) -> bool {
+    ip_proto == IpProto::Tcp as u8 || ip_proto == IpProto::Udp as u8
+}
+
+// Exceptions of `network` match the destination port, then the source port
+// so replies are covered too, then the protocol as a whole.
+#[inline(always)]
+fn spray_exception(network: &Network, flow_key: &Option<FlowKey>) -> Option<u32> {
+    let flow_key = flow_key.as_ref()?;
+    let mut key = SprayExceptionKey {
+        prefix: network.prefix,
+        prefix_len: network.prefix_len as u8,
+        ip_proto: flow_key.ip_proto,
+        port: 0,
+    };
+    if has_ports(flow_key.ip_proto) {
+        for port in [flow_key.dst_port, flow_key.src_port] {
+            key.port = u16::from_be(port);
+            if let Some(policy) = unsafe { SPRAYEXCEPT.get(&key) } {
+                return Some(*policy);
+            }
+        }
+    }
+    key.port = 0;
+    unsafe { SPRAYEXCEPT.get(&key) }.copied()
 }
 